@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine, thread_pool::RayonThreadPool};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, thread_pool::RayonThreadPool};
 use rand::{prelude::*, rngs::SmallRng};
 use sled;
 use tempfile::TempDir;
@@ -44,6 +44,56 @@ fn set_bench(c: &mut Criterion) {
     group.finish();
 }
 
+/// Many clients setting keys at the same time, each waiting until its write is synced.
+fn concurrent_set_bench(c: &mut Criterion) {
+    let num_cpus = num_cpus::get() as u32;
+    let mut group = c.benchmark_group("concurrent_set_bench");
+    group.bench_function("kvs", |b| {
+        b.iter_batched(
+            || {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                let temp_dir = TempDir::new().unwrap();
+                let options = KvStoreOptions::new()
+                    .concurrency(num_cpus)
+                    .durability(Durability::EveryWrite);
+                (KvStore::<RayonThreadPool>::open_with(temp_dir.path(), options).unwrap(), temp_dir, rt)
+            },
+            |(store, _temp_dir, rt)| {
+                rt.block_on(async {
+                    let handles: Vec<_> = (1..(1 << 7))
+                        .map(|i| tokio::spawn(store.set(format!("key{}", i), "value".to_string())))
+                        .collect();
+                    for handle in handles {
+                        let _ = handle.await;
+                    }
+                })
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("sled", |b| {
+        b.iter_batched(
+            || {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                let temp_dir = TempDir::new().unwrap();
+                (SledKvsEngine::<RayonThreadPool>::new(sled::open(&temp_dir).unwrap(), num_cpus).unwrap(), temp_dir, rt)
+            },
+            |(db, _temp_dir, rt)| {
+                rt.block_on(async {
+                    let handles: Vec<_> = (1..(1 << 7))
+                        .map(|i| tokio::spawn(db.set(format!("key{}", i), "value".to_string())))
+                        .collect();
+                    for handle in handles {
+                        let _ = handle.await;
+                    }
+                })
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
 fn get_bench(c: &mut Criterion) {
     let num_cpus = num_cpus::get() as u32;
     let mut group = c.benchmark_group("get_bench");
@@ -86,5 +136,43 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+/// Concurrent reads of sealed log files through memory maps and through the reader pool.
+/// The value cache is disabled so that every read goes to the log.
+fn get_read_path_bench(c: &mut Criterion) {
+    let num_cpus = num_cpus::get() as u32;
+    let mut group = c.benchmark_group("get_read_path_bench");
+    for (name, mmap_reads) in [("mmap", true), ("reader_pool", false)] {
+        group.bench_function(name, |b| {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions::new()
+                .concurrency(num_cpus)
+                .cache_size(0)
+                .mmap_reads(mmap_reads);
+            let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), options.clone()).unwrap();
+            for key_i in 1..(1 << 10) {
+                rt.block_on(async {
+                    let _ = store.set(format!("key{}", key_i), "value".repeat(32)).await;
+                })
+            }
+            // all logs are sealed once the store is opened again
+            drop(store);
+            let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), options).unwrap();
+            let mut rng = SmallRng::from_seed([0; 32]);
+            b.iter(|| {
+                rt.block_on(async {
+                    let handles: Vec<_> = (0..(1 << 6))
+                        .map(|_| tokio::spawn(store.get(format!("key{}", rng.gen_range::<i32, _>(1..1 << 10)))))
+                        .collect();
+                    for handle in handles {
+                        let _ = handle.await;
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, concurrent_set_bench, get_bench, get_read_path_bench);
 criterion_main!(benches);
@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, process::exit};
+use std::{net::SocketAddr, ops::Bound, process::exit, time::Duration};
 
 use clap::{Parser, Subcommand};
 
@@ -16,6 +16,13 @@ const ADDRESS_FORMAT: &str = "IP:PORT";
 struct Opt {
     #[clap(subcommand)]
     command: Option<Command>,
+    #[clap(
+        long,
+        global = true,
+        help = "Sets the named keyspace of the command instead of the default one",
+        value_name = "NAME"
+    )]
+    keyspace: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -39,6 +46,101 @@ enum Command {
         key: String,
         #[clap(name = "VALUE", help = "The string value of the key")]
         value: String,
+        #[clap(
+            long,
+            help = "Sets the seconds after which the key expires",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "incr",
+        about = "Add an integer to the integer value of a string key and print the result"
+    )]
+    Incr {
+        #[clap(name = "KEY", help = "A string key")]
+        key: String,
+        #[clap(
+            name = "DELTA",
+            help = "The integer to add",
+            default_value_t = 1,
+            allow_hyphen_values = true
+        )]
+        delta: i64,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "append",
+        about = "Append a string to the value of a string key"
+    )]
+    Append {
+        #[clap(name = "KEY", help = "A string key")]
+        key: String,
+        #[clap(name = "SUFFIX", help = "The string to append")]
+        suffix: String,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "scan",
+        about = "List the key/value pairs with keys in [START, END) or starting with a prefix"
+    )]
+    Scan {
+        #[clap(name = "START", help = "The first key, defaults to the smallest one")]
+        start: Option<String>,
+        #[clap(name = "END", help = "The key after the last one, defaults to none")]
+        end: Option<String>,
+        #[clap(
+            long,
+            help = "Lists the keys starting with a prefix instead of a range",
+            value_name = "PREFIX",
+            conflicts_with_all = &["START", "END"]
+        )]
+        prefix: Option<String>,
+        #[clap(
+            long,
+            help = "Sets how many pairs are listed at most",
+            value_name = "N",
+            default_value_t = 100
+        )]
+        limit: usize,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "ttl",
+        about = "Print the seconds left until a string key expires"
+    )]
+    Ttl {
+        #[clap(name = "KEY", help = "A string key")]
+        key: String,
         #[clap(
             long,
             help = "Sets the server address",
@@ -52,6 +154,54 @@ enum Command {
     Remove {
         #[clap(name = "KEY", help = "A string key")]
         key: String,
+        #[clap(long, help = "Succeeds even if the key doesn't exist")]
+        if_exists: bool,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "keyspace", about = "Create, drop or list the named keyspaces")]
+    Keyspace {
+        #[clap(subcommand)]
+        command: KeyspaceCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyspaceCommand {
+    #[clap(name = "create", about = "Create a named keyspace")]
+    Create {
+        #[clap(name = "NAME", help = "The name of the keyspace")]
+        name: String,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "drop", about = "Drop a named keyspace with all its keys")]
+    Drop {
+        #[clap(name = "NAME", help = "The name of the keyspace")]
+        name: String,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "list", about = "List the named keyspaces")]
+    List {
         #[clap(
             long,
             help = "Sets the server address",
@@ -76,22 +226,108 @@ async fn main() -> Result<()> {
 async fn run(opt: Opt) -> Result<()> {
     match opt.command {
         Some(Command::Get { key, addr }) => {
-            let mut client = KvsClient::connect(addr).await?;
+            let mut client = connect(addr, &opt.keyspace).await?;
             if let Some(value) = client.get(key).await? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
             }
         }
-        Some(Command::Set { key, value, addr }) => {
-            let mut client = KvsClient::connect(addr).await?;
-            client.set(key, value).await?;
+        Some(Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        }) => {
+            let mut client = connect(addr, &opt.keyspace).await?;
+            match ttl {
+                Some(secs) => {
+                    let ttl = Duration::from_secs(secs);
+                    client
+                        .set_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+                        .await?
+                }
+                None => client.set(key, value).await?,
+            }
+        }
+        Some(Command::Ttl { key, addr }) => {
+            let mut client = connect(addr, &opt.keyspace).await?;
+            match client.ttl(key.into_bytes()).await? {
+                // round up, so that a key which didn't expire yet never shows 0
+                Some(ttl) => println!("{}", ttl.as_millis().div_ceil(1000)),
+                None => println!("No expiry"),
+            }
+        }
+        Some(Command::Incr { key, delta, addr }) => {
+            let mut client = connect(addr, &opt.keyspace).await?;
+            println!("{}", client.incr_by(key.into_bytes(), delta).await?);
+        }
+        Some(Command::Append { key, suffix, addr }) => {
+            let mut client = connect(addr, &opt.keyspace).await?;
+            client.append(key.into_bytes(), suffix.into_bytes()).await?;
         }
-        Some(Command::Remove { key, addr }) => {
-            let mut client = KvsClient::connect(addr).await?;
-            client.remove(key).await?;
+        Some(Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            addr,
+        }) => {
+            let mut client = connect(addr, &opt.keyspace).await?;
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix(prefix.into_bytes(), limit).await?,
+                None => {
+                    let start = start.map_or(Bound::Unbounded, |start| {
+                        Bound::Included(start.into_bytes())
+                    });
+                    let end = end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.into_bytes()));
+                    client.scan((start, end), limit).await?
+                }
+            };
+            for (key, value) in pairs {
+                println!(
+                    "{} {}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+        }
+        Some(Command::Remove {
+            key,
+            if_exists,
+            addr,
+        }) => {
+            let mut client = connect(addr, &opt.keyspace).await?;
+            if if_exists {
+                client.remove_if_exists(key.into_bytes()).await?;
+            } else {
+                client.remove(key).await?;
+            }
         }
+        Some(Command::Keyspace { command }) => match command {
+            KeyspaceCommand::Create { name, addr } => {
+                KvsClient::connect(addr)
+                    .await?
+                    .create_keyspace(name)
+                    .await?
+            }
+            KeyspaceCommand::Drop { name, addr } => {
+                KvsClient::connect(addr).await?.drop_keyspace(name).await?
+            }
+            KeyspaceCommand::List { addr } => {
+                for name in KvsClient::connect(addr).await?.list_keyspaces().await? {
+                    println!("{}", name);
+                }
+            }
+        },
         _ => unreachable!(),
     }
     Ok(())
 }
+
+/// Connects to the server at `addr` and selects `keyspace`.
+async fn connect(addr: SocketAddr, keyspace: &Option<String>) -> Result<KvsClient> {
+    let mut client = KvsClient::connect(addr).await?;
+    client.select_keyspace(keyspace.clone());
+    Ok(client)
+}
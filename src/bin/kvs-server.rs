@@ -5,12 +5,16 @@ use tokio::{net::TcpListener};
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
 
-use clap::{ArgEnum, Parser};
+use clap::{ArgEnum, Args, Parser};
 
-use kvs::{server, KvStore, KvsEngine, Result, SledKvsEngine};
+use kvs::{
+    server, Compression, Durability, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result,
+    SledKvsEngine,
+};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
@@ -42,6 +46,102 @@ struct Opt {
         default_value_t = Pool::native,
     )]
     pool: Pool,
+    #[clap(flatten)]
+    kvs: KvsConfig,
+}
+
+#[derive(Args, Debug)]
+#[clap(next_help_heading = "KVS ENGINE")]
+struct KvsConfig {
+    #[clap(
+        long,
+        help = "Sets when the kvs engine syncs writes to disk: never, always, <N>ms or <N>b",
+        value_name = "POLICY",
+        default_value = "never",
+        parse(try_from_str)
+    )]
+    durability: Durability,
+    #[clap(
+        long,
+        help = "Sets how the kvs engine compresses values: none or lz4",
+        value_name = "ALGORITHM",
+        default_value = "none",
+        parse(try_from_str)
+    )]
+    compression: Compression,
+    #[clap(
+        long,
+        help = "Encrypts values with the 256-bit key in this file, as 32 bytes or 64 hex digits",
+        value_name = "KEY-FILE"
+    )]
+    encryption_key_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Rewrites the values encrypted with the key in this file, or the unencrypted \
+                ones if `none`, under the current key",
+        value_name = "KEY-FILE"
+    )]
+    rekey_from: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Sets how many stale bytes trigger a compaction",
+        value_name = "BYTES"
+    )]
+    compaction_threshold: Option<u64>,
+    #[clap(
+        long,
+        help = "Sets the size after which writes roll over to a new log file",
+        value_name = "BYTES"
+    )]
+    max_file_size: Option<u64>,
+    #[clap(
+        long,
+        help = "Sets how many readers with their own file handles are pooled",
+        value_name = "SIZE"
+    )]
+    reader_pool_size: Option<usize>,
+    #[clap(
+        long,
+        help = "Sets how many bytes of recently read values are cached, 0 to disable",
+        value_name = "BYTES"
+    )]
+    cache_size: Option<u64>,
+    #[clap(long, help = "Serves reads only and rejects writes")]
+    read_only: bool,
+}
+
+impl KvsConfig {
+    fn options(&self, concurrency: u32) -> Result<KvStoreOptions> {
+        let mut options = KvStoreOptions::new()
+            .concurrency(concurrency)
+            .durability(self.durability)
+            .compression(self.compression)
+            .read_only(self.read_only);
+        if let Some(path) = &self.encryption_key_file {
+            options = options.encryption_key(EncryptionKey::from_file(path)?);
+        }
+        if let Some(path) = &self.rekey_from {
+            let old_key = if path == Path::new("none") {
+                None
+            } else {
+                Some(EncryptionKey::from_file(path)?)
+            };
+            options = options.rekey_from(old_key);
+        }
+        if let Some(threshold) = self.compaction_threshold {
+            options = options.compaction_threshold(threshold);
+        }
+        if let Some(size) = self.max_file_size {
+            options = options.max_file_size(size);
+        }
+        if let Some(size) = self.reader_pool_size {
+            options = options.reader_pool_size(size);
+        }
+        if let Some(size) = self.cache_size {
+            options = options.cache_size(size);
+        }
+        Ok(options)
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -95,10 +195,25 @@ fn run(opt: Opt) -> Result<()> {
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {:?}", engine);
     info!("Thread pool: {:?}", opt.pool);
+    if engine == Engine::kvs {
+        info!("Durability: {}", opt.kvs.durability);
+        info!("Compression: {}", opt.kvs.compression);
+        if opt.kvs.encryption_key_file.is_some() {
+            info!("Encrypted");
+        }
+        if let Some(path) = &opt.kvs.rekey_from {
+            info!("Rekeying from {:?}", path);
+        }
+        if opt.kvs.read_only {
+            info!("Read-only");
+        }
+    }
     info!("Listening on {}", opt.addr);
 
-    // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{:?}", engine))?;
+    // write engine to engine file, which a read-only server only checks in `main`
+    if !opt.kvs.read_only {
+        fs::write(current_dir()?.join("engine"), format!("{:?}", engine))?;
+    }
 
     let concurrency = num_cpus::get() as u32;
     match opt.pool {
@@ -110,9 +225,12 @@ fn run(opt: Opt) -> Result<()> {
 
 fn run_with<P: ThreadPool>(engine: Engine, opt: &Opt, concurrency: u32) -> Result<()> {
     match engine {
-        Engine::kvs => run_with_engine(KvStore::<P>::open(current_dir()?, concurrency)?, opt.addr),
+        Engine::kvs => {
+            let options = opt.kvs.options(concurrency)?;
+            run_with_engine(KvStore::<P>::open_with(current_dir()?, options)?, opt.addr)
+        }
         Engine::sled => run_with_engine(
-            SledKvsEngine::<P>::new(sled::open(current_dir()?)?, concurrency)?,
+            SledKvsEngine::<P>::open(current_dir()?, concurrency)?,
             opt.addr,
         ),
     }
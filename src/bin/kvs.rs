@@ -46,7 +46,7 @@ async fn main() -> Result<()> {
         Some(("get", matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let store = KvStore::<NaiveThreadPool>::open(current_dir()?, num)?;
+            let store = KvStore::<NaiveThreadPool>::open_read_only(current_dir()?, num)?;
             if let Some(value) = store.get(key.to_string()).await? {
                 println!("{}", value);
             } else {
@@ -1,7 +1,9 @@
 use crate::{
     common::{Request, Response},
-    KvsError, Result, connection::Connection,
+    engines::{key_range, prefix_range},
+    CompareAndSwapResult, KvPair, KvsError, Result, WriteBatch, connection::Connection,
 };
+use std::{ops::RangeBounds, time::Duration};
 use tokio::{
     net::{TcpStream, ToSocketAddrs},
 };
@@ -9,6 +11,8 @@ use tokio::{
 /// Key value store client
 pub struct KvsClient {
     connection: Connection,
+    // the keyspace requests go to, `None` for the default one
+    keyspace: Option<String>,
 }
 
 impl KvsClient {
@@ -17,13 +21,57 @@ impl KvsClient {
         let socket = TcpStream::connect(addr).await?;
         let connection = Connection::new(socket);
         Ok(KvsClient {
-            connection
+            connection,
+            keyspace: None,
         })
     }
 
+    /// Select the named keyspace the following requests go to, `None` for the default one.
+    ///
+    /// The keyspace isn't checked until the next request.
+    pub fn select_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
+    }
+
+    /// Create the named keyspace `name` in the server.
+    pub async fn create_keyspace(&mut self, name: String) -> Result<()> {
+        let json = serde_json::to_string(&Request::CreateKeyspace { name })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::CreateKeyspace => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Drop the named keyspace `name` with all its keys in the server.
+    pub async fn drop_keyspace(&mut self, name: String) -> Result<()> {
+        let json = serde_json::to_string(&Request::DropKeyspace { name })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::DropKeyspace => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// List the names of the named keyspaces in the server.
+    pub async fn list_keyspaces(&mut self) -> Result<Vec<String>> {
+        let json = serde_json::to_string(&Request::ListKeyspaces)?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::ListKeyspaces(names) => Ok(names),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
     /// Get the value of a given key from the server.
-    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
-        let json = serde_json::to_string(&Request::Get { key })?;
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let json = self.encode(Request::Get { key })?;
         self.connection.write_json(&json).await?;
 
         match self.connection.read_resp().await? {
@@ -33,9 +81,9 @@ impl KvsClient {
         }
     }
 
-    /// Set the value of a string key in the server.
-    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
-        let json = serde_json::to_string(&Request::Set { key, value })?;
+    /// Set the value of a key in the server.
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let json = self.encode(Request::Set { key, value })?;
         self.connection.write_json(&json).await?;
 
         match self.connection.read_resp().await? {
@@ -45,9 +93,9 @@ impl KvsClient {
         }
     }
 
-    /// Remove a string key in the server.
-    pub async fn remove(&mut self, key: String) -> Result<()> {
-        let json = serde_json::to_string(&Request::Remove { key })?;
+    /// Remove a key in the server.
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let json = self.encode(Request::Remove { key })?;
         self.connection.write_json(&json).await?;
 
         match self.connection.read_resp().await? {
@@ -56,4 +104,191 @@ impl KvsClient {
             _ => unreachable!(),
         }
     }
+
+    /// Remove a key in the server and get its value, `None` if it doesn't exist.
+    pub async fn take(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let json = self.encode(Request::Take { key })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Take(value) => Ok(value),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Remove a key in the server if it exists and get whether it was removed.
+    pub async fn remove_if_exists(&mut self, key: Vec<u8>) -> Result<bool> {
+        let json = self.encode(Request::RemoveIfExists { key })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::RemoveIfExists(removed) => Ok(removed),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Apply all writes of `batch` atomically in the server.
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let json = self.encode(Request::Batch(batch))?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Batch => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set the value of a key to `new` in the server if its current value is `expected`.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult> {
+        let json = self.encode(Request::CompareAndSwap { key, expected, new })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::CompareAndSwap(res) => Ok(res),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Add `delta` to the integer value of a key in the server and get the new value.
+    pub async fn incr_by(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let json = self.encode(Request::IncrBy { key, delta })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::IncrBy(value) => Ok(value),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Append `suffix` to the value of a key in the server.
+    pub async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<()> {
+        let json = self.encode(Request::Append { key, suffix })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Append => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set the value of a key in the server which expires after `ttl`.
+    pub async fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let json = self.encode(Request::SetWithTtl { key, value, ttl })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::SetWithTtl => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Get the time left until a key in the server expires, `None` if it doesn't expire.
+    pub async fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let json = self.encode(Request::Ttl { key })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Ttl(ttl) => Ok(ttl),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Begin a transaction on this connection.
+    ///
+    /// Until `commit` or `abort`, `get` reads through the transaction and `set` and
+    /// `remove` are only applied on commit.
+    pub async fn begin(&mut self) -> Result<()> {
+        let json = self.encode(Request::Begin)?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Begin => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Commit the transaction of this connection if none of the keys it read changed.
+    pub async fn commit(&mut self) -> Result<()> {
+        let json = self.encode(Request::Commit)?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Commit => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Discard the transaction of this connection.
+    pub async fn abort(&mut self) -> Result<()> {
+        let json = self.encode(Request::Abort)?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Abort => Ok(()),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Get up to `limit` key/value pairs with keys in `range` from the server, ordered by key.
+    pub async fn scan<R: RangeBounds<Vec<u8>>>(&mut self, range: R, limit: usize) -> Result<Vec<KvPair>> {
+        let (start, end) = key_range(&range);
+        let json = self.encode(Request::Scan { start, end, limit })?;
+        self.connection.write_json(&json).await?;
+
+        match self.connection.read_resp().await? {
+            Response::Scan(pairs) => Ok(pairs),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Get up to `limit` key/value pairs with keys starting with `prefix` from the server,
+    /// ordered by key.
+    pub async fn scan_prefix(&mut self, prefix: Vec<u8>, limit: usize) -> Result<Vec<KvPair>> {
+        self.scan(prefix_range(prefix), limit).await
+    }
+
+    /// Get the string value of a given string key from the server.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.into_bytes()).await?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Set the value of a string key to a string in the server.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    /// Remove a string key in the server.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Encode `req` for the selected keyspace.
+    fn encode(&self, req: Request) -> Result<String> {
+        let req = match &self.keyspace {
+            Some(keyspace) => Request::InKeyspace {
+                keyspace: keyspace.clone(),
+                request: Box::new(req),
+            },
+            None => req,
+        };
+        Ok(serde_json::to_string(&req)?)
+    }
 }
@@ -1,16 +1,92 @@
+use std::{ops::Bound, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
+use crate::{CompareAndSwapResult, KvPair, WriteBatch};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Take {
+        key: Vec<u8>,
+    },
+    RemoveIfExists {
+        key: Vec<u8>,
+    },
+    Scan {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: usize,
+    },
+    Batch(WriteBatch),
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    IncrBy {
+        key: Vec<u8>,
+        delta: i64,
+    },
+    Append {
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    // `Get`, `Set` and `Remove` go through the transaction of the connection
+    // between `Begin` and `Commit` or `Abort`
+    Begin,
+    Commit,
+    Abort,
+    // runs the request in a named keyspace instead of the default one
+    InKeyspace {
+        keyspace: String,
+        request: Box<Request>,
+    },
+    CreateKeyspace {
+        name: String,
+    },
+    DropKeyspace {
+        name: String,
+    },
+    ListKeyspaces,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>),
+    Get(Option<Vec<u8>>),
     Set,
     Remove,
+    Take(Option<Vec<u8>>),
+    RemoveIfExists(bool),
+    Scan(Vec<KvPair>),
+    Batch,
+    CompareAndSwap(CompareAndSwapResult),
+    IncrBy(i64),
+    Append,
+    SetWithTtl,
+    Ttl(Option<Duration>),
+    Begin,
+    Commit,
+    Abort,
+    CreateKeyspace,
+    DropKeyspace,
+    ListKeyspaces(Vec<String>),
     Err(String),
 }
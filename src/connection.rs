@@ -44,10 +44,10 @@ impl Connection {
     }
 
     pub async fn write_json(&mut self, json: &str) -> Result<()> {
-        match self.stream.write_all(json.as_bytes()).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(KvsError::from(err)),
-        }
+        self.stream.write_all(json.as_bytes()).await?;
+        // the peer waits for the whole message, so it must not stay in the buffer
+        self.stream.flush().await?;
+        Ok(())
     }
 
     fn parse_resp(&mut self) -> Result<Option<Response>> {
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes applied atomically by `KvsEngine::write_batch`.
+///
+/// The writes are applied in the order they were added. Removing a key that
+/// doesn't exist is not an error in a batch, it simply has no effect.
+///
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"key1".to_vec(), b"value1".to_vec());
+/// batch.remove(b"key2".to_vec());
+/// assert_eq!(batch.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of a key.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Adds removing a key.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    io,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+
+/// How often expired keys are removed in the background by default.
+pub(crate) const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The shortest interval a `Reaper` runs at, so a zero interval doesn't keep it busy.
+const MIN_REAP_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A background thread which removes expired keys.
+///
+/// Expired keys are hidden from reads anyway. Removing them frees the index
+/// entries and lets compaction reclaim their records.
+///
+/// The thread is stopped and joined when the `Reaper` is dropped.
+pub(crate) struct Reaper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    /// Runs `reap` every `interval`, but at most once per millisecond, on a new thread.
+    pub(crate) fn spawn<F>(interval: Duration, mut reap: F) -> io::Result<Reaper>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let interval = interval.max(MIN_REAP_INTERVAL);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("kvs-reaper".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    reap();
+                }
+            })?;
+        Ok(Reaper {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // disconnecting the channel wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The reaper thread panicked");
+            }
+        }
+    }
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Returns when a key set now with `ttl` expires, in milliseconds since the UNIX epoch.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Returns the time left until `expires_at`, or `None` if it has passed.
+pub(crate) fn time_left(expires_at: u64) -> Option<Duration> {
+    let now = now_millis();
+    (expires_at > now).then(|| Duration::from_millis(expires_at - now))
+}
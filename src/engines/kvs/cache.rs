@@ -0,0 +1,237 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use super::CommandPos;
+
+/// Number of independently locked parts of the cache.
+const SHARDS: usize = 16;
+/// Bytes charged for an entry on top of its key and value.
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// Counters of a `KvStore` value cache, see `KvStore::cache_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Number of reads served from the cache.
+    pub hits: u64,
+    /// Number of reads which went to the log files.
+    pub misses: u64,
+    /// Number of cached values.
+    pub entries: usize,
+    /// Approximate memory used by the cached keys and values in bytes.
+    pub size: u64,
+}
+
+/// A bounded cache of recently read values, evicting the least recently used ones.
+///
+/// Every value is cached together with the position of its record. A lookup only hits
+/// if the index still points at that position, so a stale value is never returned,
+/// even if a read races with a write of the same key. The writer still removes the
+/// values it overwrites to free their memory, and moves the values compaction copies.
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<Vec<u8>, Entry>,
+    // keys by the tick of their last use, oldest first
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    size: u64,
+    capacity: u64,
+}
+
+struct Entry {
+    cmd_pos: CommandPos,
+    value: Vec<u8>,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding about `capacity` bytes, nothing if zero.
+    pub(super) fn new(capacity: u64) -> ValueCache {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    capacity: capacity / SHARDS as u64,
+                    ..Shard::default()
+                })
+            })
+            .collect();
+        ValueCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached value of `key` if it was read from `cmd_pos`.
+    pub(super) fn get(&self, key: &[u8], cmd_pos: &CommandPos) -> Option<Vec<u8>> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let value = shard.touch(key, cmd_pos);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches the `value` of `key` read from `cmd_pos`.
+    pub(super) fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos, value: Vec<u8>) {
+        self.shard(&key).lock().unwrap().insert(key, cmd_pos, value);
+    }
+
+    /// Drops the cached value of `key`.
+    pub(super) fn remove(&self, key: &[u8]) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    /// Points the cached value of `key` read from `old_pos` at `new_pos`.
+    pub(super) fn reposition(&self, key: &[u8], old_pos: &CommandPos, new_pos: CommandPos) {
+        let mut shard = self.shard(key).lock().unwrap();
+        if let Some(entry) = shard.entries.get_mut(key) {
+            if entry.cmd_pos == *old_pos {
+                entry.cmd_pos = new_pos;
+            }
+        }
+    }
+
+    /// Returns the counters of the cache.
+    pub(super) fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            stats.entries += shard.entries.len();
+            stats.size += shard.size;
+        }
+        stats
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+}
+
+impl Shard {
+    fn touch(&mut self, key: &[u8], cmd_pos: &CommandPos) -> Option<Vec<u8>> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        if entry.cmd_pos != *cmd_pos {
+            return None;
+        }
+        let key = self.lru.remove(&entry.tick).unwrap();
+        self.lru.insert(tick, key);
+        entry.tick = tick;
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, cmd_pos: CommandPos, value: Vec<u8>) {
+        let added = weight(&key, &value);
+        if added > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.size + added > self.capacity {
+            let (_, oldest) = self.lru.pop_first().unwrap();
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.size -= weight(&oldest, &entry.value);
+        }
+        let tick = self.next_tick();
+        self.lru.insert(tick, key.clone());
+        self.size += added;
+        let entry = Entry {
+            cmd_pos,
+            value,
+            tick,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.size -= weight(key, &entry.value);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// Bytes charged for caching `value` under `key`, which is held twice.
+fn weight(key: &[u8], value: &[u8]) -> u64 {
+    2 * key.len() as u64 + value.len() as u64 + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(gen: u64) -> CommandPos {
+        (gen, 0..10).into()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        // three values fit in a shard, plus some slack
+        let cache = ValueCache::new(SHARDS as u64 * (3 * weight(b"key000", &[0; 100]) + 10));
+        let shard_keys: Vec<_> = (0..1000)
+            .map(|i| format!("key{:03}", i).into_bytes())
+            .filter(|key| std::ptr::eq(cache.shard(key), cache.shard(b"key000")))
+            .take(4)
+            .collect();
+        for key in &shard_keys[..3] {
+            cache.insert(key.clone(), pos(1), vec![0; 100]);
+        }
+        assert!(cache.get(&shard_keys[0], &pos(1)).is_some());
+        cache.insert(shard_keys[3].clone(), pos(1), vec![0; 100]);
+        assert!(cache.get(&shard_keys[1], &pos(1)).is_none());
+        for key in [&shard_keys[0], &shard_keys[2], &shard_keys[3]] {
+            assert!(cache.get(key, &pos(1)).is_some());
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (4, 1, 3));
+        assert_eq!(stats.size, 3 * weight(b"key000", &[0; 100]));
+    }
+
+    #[test]
+    fn checks_positions() {
+        let cache = ValueCache::new(1024 * 1024);
+        cache.insert(b"key".to_vec(), pos(1), b"value".to_vec());
+        assert!(cache.get(b"key", &pos(2)).is_none());
+        cache.reposition(b"key", &pos(1), pos(2));
+        assert_eq!(cache.get(b"key", &pos(2)), Some(b"value".to_vec()));
+        // moved meanwhile
+        cache.reposition(b"key", &pos(1), pos(3));
+        assert!(cache.get(b"key", &pos(3)).is_none());
+        cache.remove(b"key");
+        assert!(cache.get(b"key", &pos(2)).is_none());
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    #[test]
+    fn skips_values_too_large() {
+        let cache = ValueCache::new(SHARDS as u64 * 100);
+        cache.insert(b"key".to_vec(), pos(1), vec![0; 100]);
+        assert_eq!(cache.stats().entries, 0);
+        assert!(cache.get(b"key", &pos(1)).is_none());
+    }
+}
@@ -0,0 +1,189 @@
+//! Encryption of the values in `KvStore` log records.
+//!
+//! An encrypted record has the `encrypted` flag set and its value is sealed with
+//! XChaCha20-Poly1305 under a random nonce:
+//!
+//! ```text
+//! +--------+-------+------------+-----+
+//! | key_id | nonce | ciphertext | tag |
+//! |  u64   | 24 B  |            | 16B |
+//! +--------+-------+------------+-----+
+//! ```
+//!
+//! The ciphertext holds the value as it is stored unencrypted, including the expiry
+//! time and compression. The record header and the key are authenticated along with
+//! it, so a record can't be altered or moved to another key without detection.
+//! The key id tells which key sealed the value without revealing anything about it.
+
+use std::{fmt, fs, path::Path};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use super::{record::Corruption, KvStoreOptions};
+use crate::{KvsError, Result};
+
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Number of bytes sealing adds to a value.
+pub(super) const SEAL_OVERHEAD: usize = KEY_ID_LEN + NONCE_LEN + TAG_LEN;
+
+/// A 256-bit key to encrypt the log records of a `KvStore` with.
+///
+/// The key is never written to the store, so it must be kept elsewhere. Values
+/// can't be read without it.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// Creates a key from its bytes.
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Reads a key from a file holding either the 32 bytes of the key or 64
+    /// hexadecimal digits, optionally followed by a newline.
+    ///
+    /// Returns `KvsError::InvalidEncryptionKey` if the file holds neither.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read(path)?;
+        if let Ok(bytes) = contents[..].try_into() {
+            return Ok(EncryptionKey(bytes));
+        }
+        let invalid = || KvsError::InvalidEncryptionKey(path.to_owned());
+        let hex = std::str::from_utf8(&contents)
+            .map_err(|_| invalid())?
+            .trim();
+        if hex.len() != 2 * KEY_LEN || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let mut bytes = [0; KEY_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(EncryptionKey(bytes))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never leak the key into logs
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// The keys a store seals new values with and opens the stored ones with.
+#[derive(Debug)]
+pub(super) struct Keyring {
+    current: Option<Key>,
+    // the key which is rotated away from, see `KvStoreOptions::rekey_from`
+    old: Option<Key>,
+    // whether unencrypted records are read
+    plaintext: bool,
+}
+
+struct Key {
+    id: u64,
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key({:016x})", self.id)
+    }
+}
+
+impl Key {
+    fn new(key: &EncryptionKey) -> Key {
+        let cipher = XChaCha20Poly1305::new(&key.0.into());
+        // The tag of an empty message is a pseudorandom function of the key. The zero
+        // nonce is never drawn for a record in practice.
+        let payload = Payload {
+            msg: &[],
+            aad: b"kvs key id",
+        };
+        let tag = cipher
+            .encrypt(&XNonce::default(), payload)
+            .expect("an empty message can always be sealed");
+        let id = u64::from_le_bytes(tag[..KEY_ID_LEN].try_into().unwrap());
+        Key { id, cipher }
+    }
+}
+
+impl Keyring {
+    /// Creates the keyring of a store opened with `options`.
+    ///
+    /// Unencrypted records are only read if the store isn't encrypted or is being
+    /// encrypted for the first time.
+    pub(super) fn new(options: &KvStoreOptions) -> Keyring {
+        let current = options.encryption_key.as_ref().map(Key::new);
+        let (old, plaintext) = match &options.rekey_from {
+            Some(Some(key)) => (Some(Key::new(key)), current.is_none()),
+            Some(None) => (None, true),
+            None => (None, current.is_none()),
+        };
+        Keyring {
+            current,
+            old,
+            plaintext,
+        }
+    }
+
+    /// Returns whether new values are sealed.
+    pub(super) fn encrypts(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Returns whether unencrypted records are valid.
+    pub(super) fn accepts_plaintext(&self) -> bool {
+        self.plaintext
+    }
+
+    /// Seals `value` with the current key, authenticating `aad` along with it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the store isn't encrypted.
+    pub(super) fn seal(&self, aad: &[u8], value: &[u8]) -> Vec<u8> {
+        let key = self.current.as_ref().expect("sealing without a key");
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, Payload { msg: value, aad })
+            .expect("a record value can always be sealed");
+        let mut sealed = Vec::with_capacity(SEAL_OVERHEAD + value.len());
+        sealed.extend_from_slice(&key.id.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Opens a value sealed with any key of the keyring, checking that `aad` is the
+    /// one it was sealed with.
+    pub(super) fn open(
+        &self,
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> std::result::Result<Vec<u8>, Corruption> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(Corruption::Unauthenticated);
+        }
+        let id = u64::from_le_bytes(sealed[..KEY_ID_LEN].try_into().unwrap());
+        let key = self
+            .current
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == id)
+            .ok_or(Corruption::UnknownKey(id))?;
+        let nonce = XNonce::from_slice(&sealed[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]);
+        let msg = &sealed[KEY_ID_LEN + NONCE_LEN..];
+        key.cipher
+            .decrypt(nonce, Payload { msg, aad })
+            .map_err(|_| Corruption::Unauthenticated)
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use log::{error, warn};
+
+use super::{
+    hint::{hint_path, write_hint},
+    log_path, record, BufWriterWithPos, CommandPos, Compression, KvStoreReader, KvStoreWriter,
+};
+use crate::{engines::expiry, Result};
+
+/// A compaction running in the background.
+///
+/// The writer hands it the index entries of the compacted generations and continues
+/// appending to a newer generation. The entries are copied into the compaction
+/// generation without holding the writer, which is only taken again to swap the
+/// index positions at the end. The records are decoded and written again, so they
+/// end up compressed according to the current `Compression` and sealed with the
+/// current encryption key.
+pub(super) struct Compaction {
+    pub(super) gen: u64,
+    // newest of the compacted generations
+    pub(super) last_gen: u64,
+    pub(super) path: Arc<PathBuf>,
+    pub(super) reader: KvStoreReader,
+    pub(super) compression: Compression,
+    pub(super) entries: Vec<(Vec<u8>, CommandPos)>,
+}
+
+impl Compaction {
+    /// Runs the compaction on a new thread.
+    pub(super) fn spawn(self, writer: Arc<Mutex<KvStoreWriter>>) -> io::Result<JoinHandle<()>> {
+        let gen = self.gen;
+        thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                if let Err(e) = self.run(&writer) {
+                    error!("Compaction into generation {} failed: {}", gen, e);
+                    writer.lock().unwrap().abort_compaction();
+                }
+            })
+    }
+
+    fn run(self, writer: &Mutex<KvStoreWriter>) -> Result<()> {
+        // The generation is written under a temporary name and renamed once it is
+        // complete, so a crash never leaves a partial compaction behind.
+        let tmp_path = compaction_path(&self.path, self.gen);
+        let res = self.copy_entries(&tmp_path);
+        let moved = match res {
+            Ok(moved) => moved,
+            Err(e) => {
+                if let Err(e) = fs::remove_file(&tmp_path) {
+                    error!("{:?} cannot be deleted: {}", tmp_path, e);
+                }
+                return Err(e);
+            }
+        };
+
+        // The hint is renamed after the log, so a hint file always has its log.
+        // Without a hint the log is simply replayed on open.
+        let tmp_hint_path = compaction_hint_path(&self.path, self.gen);
+        let entries = moved
+            .iter()
+            .filter_map(|(key, _, new_pos)| Some((&key[..], (*new_pos)?)));
+        let hint_written = match write_hint(&tmp_hint_path, entries) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    "Failed to write the hint file of generation {}: {}",
+                    self.gen, e
+                );
+                false
+            }
+        };
+        fs::rename(&tmp_path, log_path(&self.path, self.gen))?;
+        if hint_written {
+            fs::rename(&tmp_hint_path, hint_path(&self.path, self.gen))?;
+        }
+
+        writer
+            .lock()
+            .unwrap()
+            .finish_compaction(self.gen, self.last_gen, moved);
+        Ok(())
+    }
+
+    /// Copies the records of all entries into the compaction file, except for the
+    /// expired ones.
+    ///
+    /// Returns every key with its old and its new position, which is `None` if the
+    /// entry expired.
+    #[allow(clippy::type_complexity)]
+    fn copy_entries(
+        &self,
+        tmp_path: &Path,
+    ) -> Result<Vec<(Vec<u8>, CommandPos, Option<CommandPos>)>> {
+        let mut compaction_writer = BufWriterWithPos::new(File::create(tmp_path)?)?;
+        let mut moved = Vec::with_capacity(self.entries.len());
+        let mut new_pos = 0; // pos in the new log file
+        let now = expiry::now_millis();
+        for (key, old_pos) in &self.entries {
+            // Older records of the key are in the compacted generations too, so the
+            // expired one can be dropped without leaving a "remove" command behind.
+            if old_pos.is_expired(now) {
+                moved.push((key.clone(), *old_pos, None));
+                continue;
+            }
+            let cmd = self.reader.read_command(*old_pos)?;
+            let len = record::write_record(
+                &mut compaction_writer,
+                &cmd,
+                self.compression,
+                &self.reader.keyring,
+            )?;
+            // the copy keeps the expiry and the sequence number
+            let cmd_pos = CommandPos {
+                gen: self.gen,
+                pos: new_pos,
+                len,
+                ..*old_pos
+            };
+            moved.push((key.clone(), *old_pos, Some(cmd_pos)));
+            new_pos += len;
+        }
+        // The stale logs are deleted afterwards, so the compacted entries must be durable.
+        compaction_writer.sync_data()?;
+        Ok(moved)
+    }
+}
+
+/// Path of a compaction generation that is still being written.
+pub(super) fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.compacting", gen))
+}
+
+/// Path of the hint file of a compaction generation that is still being written.
+fn compaction_hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint.compacting", gen))
+}
+
+/// Deletes compaction files left behind by a crash during a compaction.
+pub(super) fn remove_unfinished(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compacting".as_ref()) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Hint files let `KvStore::open` rebuild the index of a compaction generation
+//! without reading the values in its log.
+//!
+//! A hint file `<gen>.hint` is written next to every compaction generation. It starts
+//! with a magic number and holds one entry per record of the log:
+//!
+//! ```text
+//! +-------+---------+-----+-----+------------+-----+
+//! | crc32 | key_len | pos | len | expires_at | key |
+//! |  u32  |   u32   | u64 | u64 |    u64     |     |
+//! +-------+---------+-----+-----+------------+-----+
+//! ```
+//!
+//! `expires_at` is zero for values which don't expire. The checksum covers the rest
+//! of the entry. A hint file that fails validation is
+//! ignored and the log is replayed instead.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crossbeam_skiplist::SkipMap;
+use log::warn;
+
+use super::{mark_stale, record::read_full, CommandPos, GenStatsMap};
+use crate::{engines::expiry, Result};
+
+/// Marks the beginning of a hint file.
+const MAGIC: u32 = 0x3248_564B; // "KVH2"
+const ENTRY_HEADER_LEN: usize = 32;
+
+/// A key with the position of its record.
+type HintEntry = (Vec<u8>, CommandPos);
+
+/// Path of the hint file of `gen`.
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// Writes a hint file with the given entries to `path` and syncs it.
+pub(super) fn write_hint<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = (&'a [u8], CommandPos)>,
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&MAGIC.to_le_bytes())?;
+    for (key, cmd_pos) in entries {
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + key.len());
+        entry.extend_from_slice(&[0; 4]); // checksum, filled in below
+        entry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&cmd_pos.pos.to_le_bytes());
+        entry.extend_from_slice(&cmd_pos.len.to_le_bytes());
+        entry.extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+        entry.extend_from_slice(key);
+        let crc = crc32fast::hash(&entry[4..]);
+        entry[..4].copy_from_slice(&crc.to_le_bytes());
+        writer.write_all(&entry)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    Ok(())
+}
+
+/// Loads the hint file of `gen` into the index and counts the stale records in `gens`.
+///
+/// Returns `false` if there is no valid hint file and the log has to be replayed.
+pub(super) fn load_hint(
+    dir: &Path,
+    gen: u64,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    gens: &mut GenStatsMap,
+) -> Result<bool> {
+    let path = hint_path(dir, gen);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    // Validate the whole file before touching the index, so that a damaged hint
+    // doesn't leave half of its entries behind when falling back to the log.
+    let entries = match read_entries(gen, BufReader::new(file))? {
+        Some(entries) => entries,
+        None => {
+            warn!("{:?} is corrupted, replaying the log instead", path);
+            return Ok(false);
+        }
+    };
+
+    let now = expiry::now_millis();
+    for (key, cmd_pos) in entries {
+        if let Some(old_cmd) = index.get(&key) {
+            mark_stale(gens, old_cmd.value());
+        }
+        if cmd_pos.is_expired(now) {
+            index.remove(&key);
+            mark_stale(gens, &cmd_pos);
+        } else {
+            index.insert(key, cmd_pos);
+        }
+    }
+    Ok(true)
+}
+
+/// Removes the hint file of `gen` if there is one.
+pub(super) fn remove_hint(dir: &Path, gen: u64) -> io::Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Reads all entries, or returns `None` if any of them is invalid.
+fn read_entries<R: Read>(gen: u64, mut reader: R) -> Result<Option<Vec<HintEntry>>> {
+    let mut magic = [0; 4];
+    if read_full(&mut reader, &mut magic)? != magic.len() || u32::from_le_bytes(magic) != MAGIC {
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+    let mut header = [0; ENTRY_HEADER_LEN];
+    loop {
+        match read_full(&mut reader, &mut header)? {
+            0 => return Ok(Some(entries)),
+            ENTRY_HEADER_LEN => {}
+            _ => return Ok(None),
+        }
+        let expected = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let key_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+        let pos = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let len = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let expires_at = u64::from_le_bytes(header[24..32].try_into().unwrap());
+
+        let mut key = Vec::new();
+        (&mut reader).take(key_len).read_to_end(&mut key)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&key);
+        if key.len() as u64 != key_len || hasher.finalize() != expected {
+            return Ok(None);
+        }
+        let cmd_pos = CommandPos::from((gen, pos..pos + len));
+        entries.push((
+            key,
+            cmd_pos.with_expiry((expires_at != 0).then_some(expires_at)),
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::error;
+
+use super::{KvStore, KvStoreOptions};
+use crate::{engines::check_keyspace_name, thread_pool::ThreadPool, KvsError, Result};
+
+/// Subdirectory of a store with the directories of its keyspaces.
+const KEYSPACES_DIR: &str = "keyspaces";
+
+/// Extension of a keyspace directory which is being deleted.
+const DROPPED_EXTENSION: &str = "dropped";
+
+/// The named keyspaces of a `KvStore`.
+///
+/// Each keyspace is a store of its own in `keyspaces/<name>`, with its own index,
+/// log files, compactions and directory lock, sharing the thread pool of the store.
+/// A keyspace is dropped by renaming its directory first, so a crash in
+/// the middle of deleting it never leaves a partial keyspace behind.
+pub(super) struct Keyspaces<P: ThreadPool> {
+    path: PathBuf,
+    options: KvStoreOptions,
+    // these stores don't refer back to the `Keyspaces`, see `KvStore::keyspaces`
+    stores: Mutex<BTreeMap<String, KvStore<P>>>,
+}
+
+impl<P: ThreadPool> Keyspaces<P> {
+    /// Opens every keyspace of the store in `dir`.
+    pub(super) fn open(dir: &Path, options: &KvStoreOptions, thread_pool: &P) -> Result<Self> {
+        let path = dir.join(KEYSPACES_DIR);
+        let mut stores = BTreeMap::new();
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let dir = entry.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            if dir.extension() == Some(DROPPED_EXTENSION.as_ref()) {
+                if !options.read_only {
+                    fs::remove_dir_all(&dir)?;
+                }
+                continue;
+            }
+            if let Some(name) = dir.file_name().and_then(|name| name.to_str()) {
+                let store = KvStore::open_store(Arc::new(dir.clone()), options, thread_pool)?;
+                stores.insert(name.to_owned(), store);
+            }
+        }
+        Ok(Keyspaces {
+            path,
+            options: options.clone(),
+            stores: Mutex::new(stores),
+        })
+    }
+
+    /// Returns the store of the keyspace `name`.
+    pub(super) fn get(&self, name: &str) -> Result<KvStore<P>> {
+        let stores = self.stores.lock().unwrap();
+        let store = stores
+            .get(name)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        Ok(store.clone())
+    }
+
+    /// Creates the keyspace `name` with an empty store running on `thread_pool`.
+    pub(super) fn create(&self, name: &str, thread_pool: &P) -> Result<()> {
+        check_keyspace_name(name)?;
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut stores = self.stores.lock().unwrap();
+        if stores.contains_key(name) {
+            return Err(KvsError::KeyspaceExists(name.to_owned()));
+        }
+        let options = self.options.clone().create_if_missing(true);
+        let dir = Arc::new(self.path.join(name));
+        let store = KvStore::open_store(dir, &options, thread_pool)?;
+        stores.insert(name.to_owned(), store);
+        Ok(())
+    }
+
+    /// Drops the keyspace `name` and deletes its directory.
+    ///
+    /// The files are deleted right away, even if handles of the keyspace are still
+    /// held elsewhere. Their writes are refused instead of being lost with the files.
+    pub(super) fn remove(&self, name: &str) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut stores = self.stores.lock().unwrap();
+        let store = stores
+            .remove(name)
+            .ok_or_else(|| KvsError::KeyspaceNotFound(name.to_owned()))?;
+        store.refuse_writes(KvsError::KeyspaceNotFound(name.to_owned()));
+        // waits for a running compaction of the keyspace if this was the last handle
+        drop(store);
+        let dir = self.path.join(name);
+        let dropped = dir.with_extension(DROPPED_EXTENSION);
+        if dropped.exists() {
+            // left behind by an earlier drop which failed to delete it
+            fs::remove_dir_all(&dropped)?;
+        }
+        fs::rename(&dir, &dropped)?;
+        drop(stores);
+        if let Err(e) = fs::remove_dir_all(&dropped) {
+            error!("{:?} cannot be deleted: {}", dropped, e);
+        }
+        Ok(())
+    }
+
+    /// Returns the names of all keyspaces, ordered by name.
+    pub(super) fn names(&self) -> Vec<String> {
+        self.stores.lock().unwrap().keys().cloned().collect()
+    }
+}
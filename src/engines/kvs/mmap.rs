@@ -0,0 +1,81 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use log::warn;
+use memmap2::Mmap;
+
+use super::{cipher::Keyring, log_path, read_decoded, record, Command, CommandPos};
+use crate::Result;
+
+/// Read-only memory maps of the sealed log files of a store, shared by all threads.
+///
+/// A sealed log is never written again, see `KvStoreWriter::roll`, so its records can
+/// be sliced out of the map without a file handle or a seek. Generations without a map,
+/// like the active log, are read through the pooled `KvStoreReader`s instead.
+pub(super) struct SealedLogs {
+    path: Arc<PathBuf>,
+    keyring: Arc<Keyring>,
+    // `None` if memory mapped reads are disabled
+    maps: Option<RwLock<BTreeMap<u64, Arc<Mmap>>>>,
+}
+
+impl SealedLogs {
+    pub(super) fn new(path: Arc<PathBuf>, keyring: Arc<Keyring>, enabled: bool) -> SealedLogs {
+        SealedLogs {
+            path,
+            keyring,
+            maps: enabled.then(|| RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    /// Maps the log file of `gen`, which must be sealed.
+    ///
+    /// A log which can't be mapped is logged and keeps being read through file handles.
+    pub(super) fn seal(&self, gen: u64) {
+        let maps = match &self.maps {
+            Some(maps) => maps,
+            None => return,
+        };
+        let path = log_path(&self.path, gen);
+        let map = File::open(&path).and_then(|file| {
+            // SAFETY: sealed log files are neither modified nor truncated, only deleted,
+            // which leaves the mapped pages of an open map intact.
+            unsafe { Mmap::map(&file) }
+        });
+        match map {
+            // an empty file can't be mapped, but is never read either
+            Ok(map) if !map.is_empty() => {
+                maps.write().unwrap().insert(gen, Arc::new(map));
+            }
+            Ok(_) => {}
+            Err(e) => warn!("{:?} cannot be memory mapped: {}", path, e),
+        }
+    }
+
+    /// Drops the maps of the generations up to `last_gen` once they are compacted.
+    ///
+    /// Reads which already hold a map finish on it.
+    pub(super) fn remove_compacted(&self, last_gen: u64) {
+        if let Some(maps) = &self.maps {
+            let mut maps = maps.write().unwrap();
+            *maps = maps.split_off(&(last_gen + 1));
+        }
+    }
+
+    /// Decodes the command at `cmd_pos` from its map, or returns `None` if its
+    /// generation isn't mapped.
+    pub(super) fn read_command(&self, cmd_pos: CommandPos) -> Option<Result<Command>> {
+        let map = Arc::clone(self.maps.as_ref()?.read().unwrap().get(&cmd_pos.gen)?);
+        let start = cmd_pos.pos as usize;
+        let end = map.len().min(start + cmd_pos.len as usize);
+        let mut bytes = map.get(start..end).unwrap_or_default();
+        Some(
+            record::read_record(&mut bytes, &self.keyring)
+                .and_then(|decoded| read_decoded(decoded, cmd_pos)),
+        )
+    }
+}
@@ -0,0 +1,2061 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::{Range, RangeBounds},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_skiplist::SkipMap;
+use log::{error, info, warn};
+use tokio::sync::oneshot;
+
+pub use self::cache::CacheStats;
+use self::cache::ValueCache;
+pub use self::cipher::EncryptionKey;
+use self::cipher::Keyring;
+use self::compaction::Compaction;
+use self::keyspace::Keyspaces;
+use self::mmap::SealedLogs;
+pub use self::options::{Compression, Durability, KvStoreOptions};
+use self::reader_pool::ReaderPool;
+use self::record::{Corruption, Decoded};
+pub use self::snapshot::KvStoreSnapshot;
+use self::snapshot::Versions;
+use super::{
+    expiry::{self, Reaper},
+    incr_value, key_range,
+    lock::DirLock,
+    BatchOp, CompareAndSwapError, CompareAndSwapResult, KvPair, KvsEngine, Version, WriteBatch,
+};
+use crate::{thread_pool::ThreadPool, KvsError, Result};
+
+mod cache;
+mod cipher;
+mod compaction;
+mod hint;
+mod keyspace;
+mod mmap;
+mod options;
+mod reader_pool;
+mod record;
+mod snapshot;
+
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
+///
+/// Key/value pairs are persisted to disk in log files. Log files are named after
+/// monotonically increasing generation numbers with a `log` extension name.
+/// Writes roll over to a new generation once the active log file is full. The older,
+/// sealed log files are never modified, only deleted once they are compacted.
+/// Each command is stored as a checksummed binary record, see the `record` module.
+/// Values can be compressed, see `Compression`, and encrypted, see `EncryptionKey`.
+/// Sealed log files are read through shared memory maps, see
+/// `KvStoreOptions::mmap_reads`.
+/// A skip list in memory stores the keys and the value locations for fast query.
+/// Recently read values are cached in memory, see `KvStoreOptions::cache_size`.
+/// Compaction generations come with a hint file of their key locations, which is
+/// loaded on open instead of replaying the whole log.
+/// Overwritten positions are kept as long as a snapshot may read them, see
+/// `KvStoreSnapshot`.
+/// Named keyspaces are stores of their own in subdirectories of the store.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # #[tokio::main]
+/// # async fn main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// use kvs::KvsEngine;
+/// let store: KvStore<RayonThreadPool> = KvStore::open(temp_dir.path(), 2)?;
+/// store.set("key".to_owned(), "value".to_owned()).await?;
+/// let val = store.get("key".to_owned()).await?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// store.set_bytes(vec![0xff, 0x00], vec![1, 2, 3]).await?;
+/// assert_eq!(store.get_bytes(vec![0xff, 0x00]).await?, Some(vec![1, 2, 3]));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvStore<P: ThreadPool> {
+    // path: Arc<PathBuf>,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    versions: Arc<Versions>,
+    // `None` if the store is opened read-only
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    // unlocks the directory once the last clone is dropped
+    _closer: Option<Arc<Closer>>,
+    // writes waiting for the next group commit
+    pending: Arc<Mutex<Vec<PendingWrite>>>,
+    thread_pool: P,
+    reader_pool: Arc<ReaderPool>,
+    cache: Arc<ValueCache>,
+    sealed: Arc<SealedLogs>,
+    // `None` in the stores of the keyspaces themselves, which would never be
+    // dropped if they referred back to the `Keyspaces` holding them
+    keyspaces: Option<Arc<Keyspaces<P>>>,
+}
+
+impl<P: ThreadPool> KvStore<P> {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist. The directory
+    /// is locked until the store and all its clones are dropped, which waits for a
+    /// running compaction.
+    ///
+    /// An incomplete record at the end of the newest log file, left behind by a crash
+    /// in the middle of a write, is truncated with a warning.
+    ///
+    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another store has the directory open.
+    ///
+    /// It propagates I/O errors during the log replay and returns
+    /// `KvsError::CorruptedLog` if a record fails validation anywhere but at the
+    /// tail of the newest log file.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<KvStore<P>> {
+        Self::open_with(path, KvStoreOptions::new().concurrency(concurrency))
+    }
+
+    /// Opens a `KvStore` with the given path for reads only.
+    ///
+    /// It neither takes the directory lock nor creates or modifies any file, so it
+    /// works on read-only mounts and next to a process writing to the directory.
+    /// The store serves the data as of when it was opened. `set` and `remove`
+    /// fail with `KvsError::ReadOnly`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the log replay and returns
+    /// `KvsError::CorruptedLog` if a record fails validation anywhere but at the
+    /// tail of the newest log file.
+    pub fn open_read_only(path: impl Into<PathBuf>, concurrency: u32) -> Result<KvStore<P>> {
+        let options = KvStoreOptions::new()
+            .concurrency(concurrency)
+            .read_only(true);
+        Self::open_with(path, options)
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// See `KvStore::open` and `KvStore::open_read_only` for how the directory is
+    /// loaded. A store opened read-only leaves a torn record at the end of the newest
+    /// log file in place and ignores it.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore<P>> {
+        let path = Arc::new(path.into());
+        let thread_pool = P::new(options.concurrency)?;
+        let store = Self::open_store(Arc::clone(&path), &options, &thread_pool)?;
+        let keyspaces = Keyspaces::open(&path, &options, &thread_pool)?;
+        Ok(KvStore {
+            keyspaces: Some(Arc::new(keyspaces)),
+            ..store
+        })
+    }
+
+    /// Opens the store in the directory `path` without its keyspaces.
+    fn open_store(path: Arc<PathBuf>, options: &KvStoreOptions, thread_pool: &P) -> Result<Self> {
+        if !options.read_only {
+            return Self::open_dir(path, options, thread_pool);
+        }
+        // A compaction of the writing process may delete log files while they
+        // are opened. The directory is loaded again in that case.
+        let mut attempts = 1;
+        loop {
+            match Self::open_dir(Arc::clone(&path), options, thread_pool) {
+                Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound && attempts < 3 => {
+                    attempts += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn open_dir(path: Arc<PathBuf>, options: &KvStoreOptions, thread_pool: &P) -> Result<Self> {
+        let concurrency = options.concurrency;
+        if options.create_if_missing && !options.read_only {
+            fs::create_dir_all(&*path)?;
+        }
+        // Only one store can write to the directory, see `DirLock`.
+        let lock = if options.read_only {
+            None
+        } else {
+            let lock = DirLock::acquire(&path)?;
+            compaction::remove_unfinished(&path)?;
+            Some(lock)
+        };
+
+        let mut readers = BTreeMap::new();
+        let index = Arc::new(SkipMap::new());
+        let keyring = Arc::new(Keyring::new(options));
+        let cache = Arc::new(ValueCache::new(options.cache_size));
+
+        let gen_list = sorted_gen_list(&path)?;
+        let mut gens = GenStatsMap::new();
+
+        for &gen in &gen_list {
+            let file = LogFile::new(File::open(log_path(&path, gen))?);
+            let mut reader = BufReaderWithPos::new(file)?;
+            let len = reader.seek(SeekFrom::End(0))?;
+            gens.entry(gen).or_default().len = len;
+            if hint::load_hint(&path, gen, &index, &mut gens)? {
+                readers.insert(gen, reader);
+                continue;
+            }
+            if let Some((pos, reason)) = load(gen, &mut reader, &*index, &mut gens, &keyring)? {
+                // Only the newest generation can have been interrupted by a crash. Compactions
+                // write their generation under a temporary name, see `compaction`.
+                if Some(&gen) != gen_list.last() || !reason.is_torn_write() {
+                    return Err(reason.at(gen, pos));
+                }
+                truncate_torn_tail(&path, gen, pos, reason, &keyring, options.read_only)?;
+                if !options.read_only {
+                    gens.entry(gen).or_default().len = pos;
+                }
+            }
+            readers.insert(gen, reader);
+        }
+
+        let sealed = SealedLogs::new(Arc::clone(&path), Arc::clone(&keyring), options.mmap_reads);
+        // the newest log of a store opened read-only may still be written by another one
+        let sealed_count = gen_list.len() - usize::from(options.read_only && !gen_list.is_empty());
+        for &gen in &gen_list[..sealed_count] {
+            sealed.seal(gen);
+        }
+        let sealed = Arc::new(sealed);
+
+        let versions = Arc::new(Versions::new(Arc::clone(&path)));
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            safe_point,
+            keyring,
+            readers: RefCell::new(readers),
+        };
+
+        let (writer, closer) = if let Some(lock) = lock {
+            let current_gen = gen_list.last().unwrap_or(&0) + 1;
+            gens.insert(current_gen, GenStats::default());
+            let expiring = index
+                .iter()
+                .filter_map(|entry| Some((entry.value().expires_at?, entry.key().clone())))
+                .collect();
+            let writer = KvStoreWriter {
+                reader: reader.clone(),
+                writer: new_log_file(&path, current_gen)?,
+                current_gen,
+                gens,
+                compaction_threshold: options.compaction_threshold,
+                max_file_size: options.max_file_size,
+                durability: options.durability,
+                compression: options.compression,
+                rekey_gen: gen_list
+                    .last()
+                    .copied()
+                    .filter(|_| options.rekey_from.is_some()),
+                unsynced: 0,
+                last_sync: Instant::now(),
+                compacting: false,
+                refusal: None,
+                path: Arc::clone(&path),
+                index: Arc::clone(&index),
+                versions: Arc::clone(&versions),
+                cache: Arc::clone(&cache),
+                sealed: Arc::clone(&sealed),
+                compaction: None,
+                expiring,
+            };
+            let writer = Arc::new(Mutex::new(writer));
+            // The background thread also syncs the records left unsynced by the
+            // interval policy, so it wakes up at least once per interval.
+            let reaper = {
+                let writer = Arc::clone(&writer);
+                let reap_interval = options.reap_interval;
+                let tick = match options.durability {
+                    Durability::Interval(interval) => interval.min(reap_interval),
+                    _ => reap_interval,
+                };
+                let mut last_reap = Instant::now();
+                Reaper::spawn(tick, move || {
+                    let mut guard = writer.lock().unwrap();
+                    guard.sync_unsynced();
+                    if last_reap.elapsed() >= reap_interval {
+                        last_reap = Instant::now();
+                        guard.reap();
+                        compact_if_needed(&writer, &mut guard);
+                    }
+                })?
+            };
+            let closer = Closer {
+                writer: Arc::clone(&writer),
+                reaper: Some(reaper),
+                _lock: lock,
+            };
+            (Some(writer), Some(Arc::new(closer)))
+        } else {
+            (None, None)
+        };
+
+        let reader_pool_size = options.reader_pool_size.unwrap_or(concurrency as usize + 1);
+        let reader_pool = Arc::new(ReaderPool::new(
+            reader,
+            reader_pool_size,
+            options.read_only,
+        )?);
+
+        Ok(KvStore {
+            // path,
+            index,
+            versions,
+            writer,
+            _closer: closer,
+            pending: Arc::new(Mutex::new(Vec::new())),
+            thread_pool: thread_pool.clone(),
+            reader_pool,
+            cache,
+            sealed,
+            keyspaces: None,
+        })
+    }
+
+    /// Returns the hit and miss counters and the size of the value cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Runs `job` on the thread pool.
+    fn spawn<T, F>(&self, job: F) -> Pin<Box<dyn Future<Output = Result<T>> + Send>>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            if tx.send(job()).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        let fut = async move {
+            match rx.await {
+                Ok(ret) => ret,
+                Err(_e) => Err(KvsError::StringError("tokio recv error".to_owned())),
+            }
+        };
+        Box::pin(fut)
+    }
+
+    /// Makes all further writes to the store fail with `e`, like those through
+    /// handles of a dropped keyspace.
+    fn refuse_writes(&self, e: KvsError) {
+        if let Some(writer) = &self.writer {
+            writer.lock().unwrap().refusal = Some(e);
+        }
+    }
+
+    /// Returns the keyspaces, which every handle but those kept by them has.
+    fn keyspaces(&self) -> Arc<Keyspaces<P>> {
+        let keyspaces = self.keyspaces.as_ref();
+        Arc::clone(keyspaces.expect("a handle of a store has its keyspaces"))
+    }
+
+    /// Queues a write for the next group commit.
+    ///
+    /// The job spawned here commits every write queued so far once it gets the writer.
+    /// Writes queued while another commit is in progress are thus committed together,
+    /// and a job may find the queue empty because an earlier job took its write along.
+    fn submit<T: Send + 'static>(
+        &self,
+        op: WriteOp,
+        extract: fn(Outcome) -> Result<T>,
+    ) -> Pin<Box<dyn Future<Output = Result<T>> + Send>> {
+        let writer = match &self.writer {
+            Some(writer) => Arc::clone(writer),
+            None => return Box::pin(async { Err(KvsError::ReadOnly) }),
+        };
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().push(PendingWrite { op, tx });
+        let pending = self.pending.clone();
+        self.thread_pool.spawn(move || {
+            let mut guard = writer.lock().unwrap();
+            let group = mem::take(&mut *pending.lock().unwrap());
+            if group.is_empty() {
+                return;
+            }
+            guard.commit(group);
+            compact_if_needed(&writer, &mut guard);
+        });
+        let fut = async move {
+            match rx.await {
+                Ok(ret) => ret.and_then(extract),
+                Err(_e) => Err(KvsError::StringError("tokio recv error".to_owned())),
+            }
+        };
+        Box::pin(fut)
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for KvStore<P> {
+    type Snapshot = KvStoreSnapshot<P>;
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// The returned future resolves once the record is written according to the
+    /// durability policy. Concurrent writes share a single flush and sync.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.submit(WriteOp::Command(Command::set(key, value)), done)
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>> {
+        let fut = self.get_versioned(key);
+        Box::pin(async move { Ok(fut.await?.0) })
+    }
+
+    /// Gets the value of a given key together with its version.
+    ///
+    /// The version is the sequence number of the commit which wrote the value.
+    fn get_versioned(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(Option<Vec<u8>>, Version)>> + Send>> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let cache = self.cache.clone();
+        let sealed = self.sealed.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = (|| {
+                let now = expiry::now_millis();
+                let cmd_pos = index
+                    .get(&key)
+                    .map(|entry| *entry.value())
+                    .filter(|cmd_pos| !cmd_pos.is_expired(now));
+                let version = Version::seq(cmd_pos.map(|cmd_pos| cmd_pos.seq));
+                match cmd_pos {
+                    Some(cmd_pos) => {
+                        let value = read_value(&key, cmd_pos, &cache, &sealed, &reader_pool)?;
+                        Ok((Some(value), version))
+                    }
+                    None => Ok((None, version)),
+                }
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        let fut = async move {
+            match rx.await {
+                Ok(ret) => ret,
+                Err(_e) => Err(KvsError::StringError("tokio recv error".to_owned())),
+            }
+        };
+        Box::pin(fut)
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn remove_bytes(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.submit(WriteOp::Command(Command::remove(key)), done)
+    }
+
+    /// Removes a given key and returns its value.
+    ///
+    /// The value is read under the writer lock, so no other write can slip in
+    /// between reading and removing it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading or writing the log.
+    fn take(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>> {
+        self.submit(WriteOp::Take(key), |outcome| match outcome {
+            Outcome::Taken(value) => Ok(value),
+            _ => Err(KvsError::UnexpectedCommandType),
+        })
+    }
+
+    /// Removes a given key if it exists.
+    ///
+    /// Nothing is written to the log for a missing key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn remove_if_exists(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> {
+        self.submit(WriteOp::RemoveIfExists(key), |outcome| match outcome {
+            Outcome::Removed(removed) => Ok(removed),
+            _ => Err(KvsError::UnexpectedCommandType),
+        })
+    }
+
+    /// Applies all writes of `batch` atomically.
+    ///
+    /// The batch is written as a single log record, which is replayed either
+    /// completely or not at all.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn write_batch(&self, batch: WriteBatch) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.submit(WriteOp::Batch(batch_cmds(batch)), done)
+    }
+
+    /// Applies all writes of `batch` atomically if every key in `reads` still has
+    /// the version it was read at.
+    ///
+    /// The versions are checked under the writer lock, and the writes are committed
+    /// like a batch.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key changed.
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Version)>,
+        batch: WriteBatch,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let cmds = batch_cmds(batch);
+        self.submit(WriteOp::Transaction { reads, cmds }, done)
+    }
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// The comparison is done under the writer lock, so it sees every write
+    /// committed before.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading or writing the log.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Pin<Box<dyn Future<Output = Result<CompareAndSwapResult>> + Send>> {
+        let op = WriteOp::CompareAndSwap { key, expected, new };
+        self.submit(op, |outcome| match outcome {
+            Outcome::Swapped(res) => Ok(res),
+            _ => Err(KvsError::UnexpectedCommandType),
+        })
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// The value is read and written under the writer lock, so concurrent
+    /// increments are never lost.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the value is not an `i64` and
+    /// `KvsError::IntegerOverflow` if the new value doesn't fit in one.
+    ///
+    /// It propagates I/O errors during reading or writing the log.
+    fn incr_by(
+        &self,
+        key: Vec<u8>,
+        delta: i64,
+    ) -> Pin<Box<dyn Future<Output = Result<i64>> + Send>> {
+        self.submit(WriteOp::IncrBy { key, delta }, |outcome| match outcome {
+            Outcome::Incremented(n) => Ok(n),
+            _ => Err(KvsError::UnexpectedCommandType),
+        })
+    }
+
+    /// Appends `suffix` to the value of a key.
+    ///
+    /// Like `incr_by`, it is done under the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading or writing the log.
+    fn append(
+        &self,
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.submit(WriteOp::Append { key, suffix }, done)
+    }
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// The expiry time is stored in the log record. An expired key reads as missing
+    /// right away, and is removed from the index by a background thread.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log.
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let cmd = Command::expiring(key, value, expiry::expires_at(ttl));
+        self.submit(WriteOp::Command(cmd), done)
+    }
+
+    /// Returns the time left until a key expires, or `None` if it doesn't expire.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn ttl(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Option<Duration>>> + Send>> {
+        let expires_at = self.index.get(&key).map(|entry| entry.value().expires_at);
+        let res = match expires_at {
+            Some(Some(expires_at)) => expiry::time_left(expires_at)
+                .map(Some)
+                .ok_or(KvsError::KeyNotFound),
+            Some(None) => Ok(None),
+            None => Err(KvsError::KeyNotFound),
+        };
+        Box::pin(async move { res })
+    }
+
+    /// Returns up to `limit` key/value pairs with keys in `range`, ordered by key.
+    ///
+    /// The keys are taken from the index first and their values are read afterwards,
+    /// so a scan concurrent with writes can return a mix of older and newer values.
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>> {
+        let range = key_range(&range);
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let cache = self.cache.clone();
+        let sealed = self.sealed.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let now = expiry::now_millis();
+            let entries: Vec<_> = index
+                .range(range)
+                .filter(|entry| !entry.value().is_expired(now))
+                .take(limit)
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect();
+            let res = entries
+                .into_iter()
+                .map(|(key, cmd_pos)| {
+                    let value = read_value(&key, cmd_pos, &cache, &sealed, &reader_pool)?;
+                    Ok((key, value))
+                })
+                .collect();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        let fut = async move {
+            match rx.await {
+                Ok(ret) => ret,
+                Err(_e) => Err(KvsError::StringError("tokio recv error".to_owned())),
+            }
+        };
+        Box::pin(fut)
+    }
+
+    /// Takes a snapshot of the data.
+    ///
+    /// The snapshot only pins the sequence number of the last commit, so taking
+    /// it is cheap. Holding it keeps the values it can read on disk, see
+    /// `KvStoreSnapshot`.
+    fn snapshot(&self) -> Pin<Box<dyn Future<Output = Result<KvStoreSnapshot<P>>> + Send>> {
+        let snapshot = KvStoreSnapshot::new(
+            Arc::clone(&self.index),
+            Arc::clone(&self.versions),
+            self.thread_pool.clone(),
+            Arc::clone(&self.reader_pool),
+            self._closer.clone(),
+        );
+        Box::pin(async move { Ok(snapshot) })
+    }
+
+    /// Returns the named keyspace `name`.
+    ///
+    /// The keyspaces are opened with the store, so this only looks it up.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if the keyspace doesn't exist.
+    fn keyspace(&self, name: &str) -> Result<KvStore<P>> {
+        let keyspaces = self.keyspaces();
+        let store = keyspaces.get(name)?;
+        Ok(KvStore {
+            keyspaces: Some(keyspaces),
+            ..store
+        })
+    }
+
+    /// Creates the named keyspace `name`.
+    ///
+    /// The keyspace is an empty store in the subdirectory `keyspaces/<name>`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceExists` if the keyspace exists already and
+    /// `KvsError::InvalidKeyspaceName` if the name is not valid.
+    ///
+    /// It propagates I/O errors during creating the directory.
+    fn create_keyspace(&self, name: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keyspaces = self.keyspaces();
+        let name = name.to_owned();
+        let thread_pool = self.thread_pool.clone();
+        self.spawn(move || keyspaces.create(&name, &thread_pool))
+    }
+
+    /// Drops the named keyspace `name` together with all its keys.
+    ///
+    /// Its directory is deleted right away. Handles of the keyspace which are
+    /// still held elsewhere must not be used anymore: their writes fail with
+    /// `KvsError::KeyspaceNotFound` and their reads may fail as well.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if the keyspace doesn't exist.
+    ///
+    /// It propagates I/O errors during deleting the directory.
+    fn drop_keyspace(&self, name: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keyspaces = self.keyspaces();
+        let name = name.to_owned();
+        self.spawn(move || keyspaces.remove(&name))
+    }
+
+    /// Returns the names of all named keyspaces, ordered by name.
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>> {
+        let names = self.keyspaces().names();
+        Box::pin(async move { Ok(names) })
+    }
+}
+
+/// A single thread reader.
+///
+/// Each `KvStore` instance has its own `KvStoreReader` and
+/// `KvStoreReader`s open the same files separately. So the user
+/// can read concurrently through multiple `KvStore`s in different
+/// threads.
+#[derive(Debug)]
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // log files of older generations are stale
+    safe_point: Arc<AtomicU64>,
+    keyring: Arc<Keyring>,
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<LogFile>>>,
+}
+
+impl KvStoreReader {
+    /// Close file handles with generation number less than safe_point.
+    ///
+    /// `safe_point` is updated after a compaction finishes, to the generation following
+    /// the compacted ones. The compaction generation contains the live entries of the
+    /// compacted generations and the in-memory index contains no entries with generation
+    /// number less than safe_point. So we can safely close those file handles and the
+    /// stale files can be deleted.
+    fn close_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        while !readers.is_empty() {
+            let first_gen = *readers.keys().next().unwrap();
+            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
+                break;
+            }
+            readers.remove(&first_gen);
+        }
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReaderWithPos<LogFile>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        // We don't use entry API here because we want the errors to be propogated.
+        if !readers.contains_key(&cmd_pos.gen) {
+            let file = LogFile::new(File::open(log_path(&self.path, cmd_pos.gen))?);
+            readers.insert(cmd_pos.gen, BufReaderWithPos::new(file)?);
+        }
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let cmd_reader = reader.take(cmd_pos.len);
+        f(cmd_reader)
+    }
+
+    // Read the log file at the given `CommandPos` and decode it to `Command`.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            let decoded = record::read_record(&mut cmd_reader, &self.keyring)?;
+            read_decoded(decoded, cmd_pos)
+        })
+    }
+
+    /// Clones the reader together with the handles of all files open in this one.
+    ///
+    /// The handles are duplicated rather than opened again, so the clone can still
+    /// read files which were deleted meanwhile.
+    fn open_all(&self) -> Result<KvStoreReader> {
+        let mut readers = BTreeMap::new();
+        for (&gen, reader) in self.readers.borrow().iter() {
+            let file = reader.reader.get_ref().try_clone()?;
+            readers.insert(gen, BufReaderWithPos::new(file)?);
+        }
+        Ok(KvStoreReader {
+            readers: RefCell::new(readers),
+            ..self.clone()
+        })
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        Self {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            keyring: Arc::clone(&self.keyring),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// Extracts the command of the record read at `cmd_pos`.
+fn read_decoded(decoded: Decoded, cmd_pos: CommandPos) -> Result<Command> {
+    match decoded {
+        Decoded::Record(cmd, _) => Ok(cmd),
+        // the index only points at the records nested in a batch
+        Decoded::Batch(..) => Err(KvsError::UnexpectedCommandType),
+        Decoded::Eof => Err(Corruption::Truncated.at(cmd_pos.gen, cmd_pos.pos)),
+        Decoded::Corrupted(reason) => Err(reason.at(cmd_pos.gen, cmd_pos.pos)),
+    }
+}
+
+struct KvStoreWriter {
+    reader: KvStoreReader,
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    // the size of every log file and the number of its bytes representing
+    // "stale" commands that could be deleted during a compaction
+    gens: GenStatsMap,
+    compaction_threshold: u64,
+    // size after which writes roll over to a new generation
+    max_file_size: u64,
+    durability: Durability,
+    compression: Compression,
+    // newest generation which may hold records to rewrite under the current key
+    rekey_gen: Option<u64>,
+    // the number of bytes written to the active log since the last sync
+    unsynced: u64,
+    last_sync: Instant,
+    // whether a background compaction is running
+    compacting: bool,
+    // the error all writes fail with once the writer can't go on
+    refusal: Option<KvsError>,
+    path: Arc<PathBuf>,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    versions: Arc<Versions>,
+    cache: Arc<ValueCache>,
+    sealed: Arc<SealedLogs>,
+    // thread of the last compaction
+    compaction: Option<JoinHandle<()>>,
+    // expiry times of the keys set with a TTL, outdated once a key is written again
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl KvStoreWriter {
+    /// Commits a group of writes with a single flush and sync.
+    ///
+    /// All records are appended first and the index is only updated once they are
+    /// flushed, so readers never see a position that isn't in the file yet. A remove
+    /// of a missing key fails on its own, an I/O error fails every write of the group
+    /// and the records appended so far are dropped from the log again.
+    fn commit(&mut self, group: Vec<PendingWrite>) {
+        if let Some(e) = &self.refusal {
+            for PendingWrite { tx, .. } in group {
+                respond(tx, Err(share_error(e)));
+            }
+            return;
+        }
+        let start = self.writer.pos;
+        let mut appended = Vec::with_capacity(group.len());
+        let mut staged = Staged::new();
+        let mut failure = None;
+
+        for PendingWrite { op, tx } in group {
+            if let Some(e) = &failure {
+                respond(tx, Err(share_error(e)));
+                continue;
+            }
+            match self.append(op, &mut staged) {
+                Ok(written) => appended.push((tx, written)),
+                Err(e) => {
+                    respond(tx, Err(share_error(&e)));
+                    failure = Some(e);
+                }
+            }
+        }
+
+        if failure.is_none() {
+            let written = self.writer.pos - start;
+            let res = self.writer.flush().map_err(KvsError::from);
+            if let Err(e) = res.and_then(|_| self.sync_if_needed(written)) {
+                failure = Some(e);
+            }
+        }
+
+        if let Some(e) = &failure {
+            for (tx, _) in appended {
+                respond(tx, Err(share_error(e)));
+            }
+            self.rewind(start);
+        } else {
+            // The whole group is applied before anyone is answered, so a snapshot
+            // taken after a write completed always sees it.
+            let mut outcomes = Vec::with_capacity(appended.len());
+            let versions = Arc::clone(&self.versions);
+            versions.commit(|seq, retain| {
+                for (tx, written) in appended {
+                    // the header of a batch is dropped in the next compaction
+                    let stats = self.gens.entry(self.current_gen).or_default();
+                    stats.len += written.overhead;
+                    stats.stale += written.overhead;
+                    for (cmd, range) in written.records {
+                        self.apply(cmd, range, seq, retain);
+                    }
+                    outcomes.push((tx, written.outcome));
+                }
+            });
+            for (tx, outcome) in outcomes {
+                respond(tx, outcome);
+            }
+        }
+
+        if failure.is_none() && self.writer.pos >= self.max_file_size {
+            // The writes are committed anyway, so keep appending to the full log
+            // and try again after the next commit.
+            if let Err(e) = self.roll(self.current_gen + 1) {
+                error!("Failed to roll over to a new log file: {}", e);
+            }
+        }
+    }
+
+    /// Appends the records of `op` to the active log without flushing them.
+    ///
+    /// A rejected write, like removing a missing key, only fails its own outcome.
+    /// The returned error fails the whole commit.
+    fn append(&mut self, op: WriteOp, staged: &mut Staged) -> Result<Appended> {
+        let (cmds, outcome) = match op {
+            WriteOp::Command(cmd) => {
+                if matches!(cmd, Command::Remove { .. }) && !self.exists(staged, cmd.key()) {
+                    return Ok(Appended::outcome(Err(KvsError::KeyNotFound)));
+                }
+                (vec![cmd], Outcome::Done)
+            }
+            WriteOp::Batch(cmds) => (self.effective(staged, cmds), Outcome::Done),
+            WriteOp::Transaction { reads, cmds } => {
+                // a key written earlier in the group has a newer version already
+                let changed = reads.iter().any(|(key, version)| {
+                    let seq = self.live_pos(key).map(|cmd_pos| cmd_pos.seq);
+                    staged.contains_key(key) || Version::seq(seq) != *version
+                });
+                if changed {
+                    return Ok(Appended::outcome(Err(KvsError::TransactionConflict)));
+                }
+                (self.effective(staged, cmds), Outcome::Done)
+            }
+            WriteOp::CompareAndSwap { key, expected, new } => {
+                let current = match self.current_value(staged, &key)? {
+                    Ok(current) => current,
+                    Err(e) => return Ok(Appended::outcome(Err(e))),
+                };
+                if current != expected {
+                    let mismatch = Err(CompareAndSwapError { current });
+                    return Ok(Appended::outcome(Ok(Outcome::Swapped(mismatch))));
+                }
+                let cmds = match new {
+                    Some(value) => vec![Command::set(key, value)],
+                    None if current.is_some() => vec![Command::remove(key)],
+                    None => Vec::new(),
+                };
+                (cmds, Outcome::Swapped(Ok(())))
+            }
+            WriteOp::IncrBy { key, delta } => {
+                let current = match self.current_value(staged, &key)? {
+                    Ok(current) => current,
+                    Err(e) => return Ok(Appended::outcome(Err(e))),
+                };
+                match incr_value(current.as_deref(), delta) {
+                    Ok(n) => {
+                        let cmd = Command::set(key, n.to_string().into_bytes());
+                        (vec![cmd], Outcome::Incremented(n))
+                    }
+                    Err(e) => return Ok(Appended::outcome(Err(e))),
+                }
+            }
+            WriteOp::Append { key, suffix } => {
+                let mut value = match self.current_value(staged, &key)? {
+                    Ok(current) => current.unwrap_or_default(),
+                    Err(e) => return Ok(Appended::outcome(Err(e))),
+                };
+                value.extend(suffix);
+                (vec![Command::set(key, value)], Outcome::Done)
+            }
+            WriteOp::Take(key) => match self.current_value(staged, &key)? {
+                Ok(Some(value)) => (vec![Command::remove(key)], Outcome::Taken(Some(value))),
+                Ok(None) => return Ok(Appended::outcome(Ok(Outcome::Taken(None)))),
+                Err(e) => return Ok(Appended::outcome(Err(e))),
+            },
+            WriteOp::RemoveIfExists(key) => {
+                if !self.exists(staged, &key) {
+                    return Ok(Appended::outcome(Ok(Outcome::Removed(false))));
+                }
+                (vec![Command::remove(key)], Outcome::Removed(true))
+            }
+            #[cfg(test)]
+            WriteOp::Fail => return Err(KvsError::StringError("injected failure".to_owned())),
+        };
+
+        // a record which can't be encoded fails only its own write
+        let encoded = match cmds.len() {
+            0 => Ok((Vec::new(), Vec::new())),
+            1 => record::encode_record(&cmds[0], self.compression, &self.reader.keyring)
+                .map(|buf| (buf, Vec::new())),
+            _ => record::encode_batch(&cmds, self.compression, &self.reader.keyring),
+        };
+        let (buf, ranges) = match encoded {
+            Ok(encoded) => encoded,
+            Err(e) => return Ok(Appended::outcome(Err(e))),
+        };
+        let pos = self.writer.pos;
+        self.writer.write_all(&buf)?;
+        let len = buf.len() as u64;
+        let (records, overhead) = match cmds.len() {
+            0 => (Vec::new(), 0),
+            1 => (vec![(cmds.into_iter().next().unwrap(), pos..pos + len)], 0),
+            _ => {
+                let records = cmds
+                    .into_iter()
+                    .zip(ranges)
+                    .map(|(cmd, range)| (cmd, pos + range.start..pos + range.end))
+                    .collect();
+                (records, record::HEADER_LEN as u64)
+            }
+        };
+        for (cmd, range) in &records {
+            let range = matches!(cmd, Command::Set { .. }).then(|| range.clone());
+            staged.insert(cmd.key().to_owned(), range);
+        }
+        Ok(Appended {
+            records,
+            overhead,
+            outcome: Ok(outcome),
+        })
+    }
+
+    /// Drops the removals of missing keys from the commands of a batch, which are
+    /// no-ops in a batch.
+    fn effective(&self, staged: &Staged, cmds: Vec<Command>) -> Vec<Command> {
+        let mut live = HashMap::new();
+        cmds.into_iter()
+            .filter(|cmd| {
+                let key = cmd.key();
+                let exists = live
+                    .get(key)
+                    .copied()
+                    .unwrap_or_else(|| self.exists(staged, key));
+                let is_set = matches!(cmd, Command::Set { .. });
+                live.insert(key.to_owned(), is_set);
+                is_set || exists
+            })
+            .collect()
+    }
+
+    /// Returns whether `key` exists after the records appended so far.
+    fn exists(&self, staged: &Staged, key: &[u8]) -> bool {
+        staged
+            .get(key)
+            .map(Option::is_some)
+            .unwrap_or_else(|| self.live_pos(key).is_some())
+    }
+
+    /// Returns the position of the value of `key` unless it expired.
+    fn live_pos(&self, key: &[u8]) -> Option<CommandPos> {
+        let now = expiry::now_millis();
+        let entry = self.index.get(key)?;
+        Some(*entry.value()).filter(|cmd_pos| !cmd_pos.is_expired(now))
+    }
+
+    /// Reads the value of `key` after the records appended so far.
+    ///
+    /// A failed read, like a corrupted record, only fails the write it is for, so it
+    /// is returned in the inner result. The outer error of a failed flush fails the
+    /// whole commit.
+    fn current_value(&mut self, staged: &Staged, key: &[u8]) -> Result<Result<Option<Vec<u8>>>> {
+        let cmd_pos = match staged.get(key) {
+            Some(Some(range)) => {
+                // make the appended record readable
+                self.writer.flush()?;
+                (self.current_gen, range.clone()).into()
+            }
+            Some(None) => return Ok(Ok(None)),
+            None => match self.live_pos(key) {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(Ok(None)),
+            },
+        };
+        Ok(match self.reader.read_command(cmd_pos) {
+            Ok(Command::Set { value, .. }) => Ok(Some(value)),
+            Ok(Command::Remove { .. }) => Err(KvsError::UnexpectedCommandType),
+            Err(e) => Err(e),
+        })
+    }
+
+    /// Updates the index with a command of the commit `seq` whose record is at
+    /// `range` in the active log.
+    ///
+    /// The positions it supersedes are kept for the snapshots if `retain` is set.
+    fn apply(&mut self, cmd: Command, range: Range<u64>, seq: u64, retain: bool) {
+        let cmd_pos = CommandPos {
+            seq,
+            ..(self.current_gen, range).into()
+        };
+        self.gens.entry(self.current_gen).or_default().len += cmd_pos.len;
+        if retain {
+            if let Some(old_cmd) = self.index.get(cmd.key()) {
+                self.versions.retain(cmd.key(), seq, *old_cmd.value());
+            }
+        }
+        match cmd {
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                if let Some(old_cmd) = self.index.get(&key) {
+                    mark_stale(&mut self.gens, old_cmd.value());
+                    self.cache.remove(&key);
+                }
+                if let Some(expires_at) = expires_at {
+                    self.expiring.insert((expires_at, key.clone()));
+                }
+                self.index.insert(key, cmd_pos.with_expiry(expires_at));
+            }
+            Command::Remove { key } => {
+                if let Some(old_cmd) = self.index.remove(&key) {
+                    mark_stale(&mut self.gens, old_cmd.value());
+                    self.cache.remove(&key);
+                }
+                // the "remove" command itself can be deleted in the next compaction
+                // so we count it as stale
+                mark_stale(&mut self.gens, &cmd_pos);
+            }
+        }
+    }
+
+    /// Drops everything appended to the active log after `pos`, like the records of a
+    /// failed commit, so they are neither replayed nor flushed with the next commit.
+    ///
+    /// If the log can't be rewound, the writer refuses all further writes.
+    fn rewind(&mut self, pos: u64) {
+        if let Err(e) = self.truncate_active(pos) {
+            error!("Failed to rewind the log, refusing further writes: {}", e);
+            self.refusal = Some(e);
+        }
+    }
+
+    fn truncate_active(&mut self, pos: u64) -> Result<()> {
+        let file = self.writer.writer.get_ref().try_clone()?;
+        // the buffered records are dropped instead of flushed
+        let rejected = mem::replace(&mut self.writer, BufWriterWithPos::new(file)?);
+        drop(rejected.writer.into_parts());
+        self.writer.writer.get_ref().set_len(pos)?;
+        // the log is appended to, so this only moves the position
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Syncs the active log according to the durability policy after `len` more
+    /// bytes were flushed to it.
+    fn sync_if_needed(&mut self, len: u64) -> Result<()> {
+        self.unsynced += len;
+        let due = match self.durability {
+            Durability::Never => false,
+            Durability::EveryWrite => true,
+            Durability::Interval(interval) => self.last_sync.elapsed() >= interval,
+            Durability::Bytes(bytes) => self.unsynced >= bytes,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the records written since the last sync under `Durability::Interval`,
+    /// which mustn't stay unsynced for longer than the interval if no write follows.
+    fn sync_unsynced(&mut self) {
+        if matches!(self.durability, Durability::Interval(_)) && self.unsynced > 0 {
+            if let Err(e) = self.sync() {
+                error!("Failed to sync the log: {}", e);
+            }
+        }
+    }
+
+    /// Syncs everything written to the active log to disk.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.sync_data()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Seals the active log and continues writing in a new log file of generation `gen`.
+    ///
+    /// A sealed log is synced regardless of the durability policy and never written
+    /// again, so it can be copied safely until a compaction deletes it. Only the
+    /// active log can thus end with a torn record after a crash.
+    fn roll(&mut self, gen: u64) -> Result<()> {
+        let writer = new_log_file(&self.path, gen)?;
+        self.sync()?;
+        self.writer = writer;
+        self.sealed.seal(self.current_gen);
+        self.current_gen = gen;
+        self.gens.insert(gen, GenStats::default());
+        Ok(())
+    }
+
+    /// Picks the generations to compact: the longest run of oldest generations that
+    /// is at least half stale, if it has more stale bytes than the threshold. While
+    /// rekeying, the run covers at least all generations which existed on open.
+    ///
+    /// Returns the newest generation of the run. Older records of a key are always
+    /// compacted together with newer ones, so a removed key can't come back from an
+    /// older log once its "remove" record is compacted away.
+    fn compaction_prefix(&self) -> Option<u64> {
+        let (mut len, mut stale) = (0, 0);
+        let mut prefix = None;
+        for (&gen, stats) in &self.gens {
+            len += stats.len;
+            stale += stats.stale;
+            if stale > self.compaction_threshold && stale * 2 >= len {
+                prefix = Some(gen);
+            }
+        }
+        prefix.max(self.rekey_gen)
+    }
+
+    /// Starts a compaction if enough stale bytes piled up and none is running yet.
+    ///
+    /// The active log is sealed and the returned `Compaction` copies the live entries
+    /// of the generations up to `compaction_prefix` into a new generation, between the
+    /// sealed logs and the one writes continue in.
+    fn start_compaction(&mut self) -> Option<Compaction> {
+        if self.compacting || self.refusal.is_some() {
+            return None;
+        }
+        let last_gen = self.compaction_prefix()?;
+
+        // increase current gen by 2. current_gen + 1 is for the compaction file
+        let compaction_gen = self.current_gen + 1;
+        if let Err(e) = self.roll(self.current_gen + 2) {
+            error!("Failed to start a compaction: {}", e);
+            return None;
+        }
+        self.compacting = true;
+
+        let entries = self
+            .index
+            .iter()
+            .filter(|entry| entry.value().gen <= last_gen)
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        Some(Compaction {
+            gen: compaction_gen,
+            last_gen,
+            path: Arc::clone(&self.path),
+            reader: self.reader.clone(),
+            compression: self.compression,
+            entries,
+        })
+    }
+
+    /// Points the index at the compaction generation and removes the compacted log
+    /// files up to `last_gen`.
+    ///
+    /// Entries overwritten or removed while the compaction was running keep their
+    /// newer position, so their copies in the compaction generation are stale.
+    /// Expired entries which weren't copied are removed from the index.
+    #[allow(clippy::type_complexity)]
+    fn finish_compaction(
+        &mut self,
+        compaction_gen: u64,
+        last_gen: u64,
+        moved: Vec<(Vec<u8>, CommandPos, Option<CommandPos>)>,
+    ) {
+        let mut stats = GenStats::default();
+        for (key, old_pos, new_pos) in moved {
+            let unchanged = self
+                .index
+                .get(&key)
+                .is_some_and(|entry| *entry.value() == old_pos);
+            match new_pos {
+                Some(new_pos) => {
+                    stats.len += new_pos.len;
+                    if unchanged {
+                        self.cache.reposition(&key, &old_pos, new_pos);
+                        self.index.insert(key, new_pos);
+                    } else {
+                        stats.stale += new_pos.len;
+                    }
+                }
+                None if unchanged => {
+                    self.cache.remove(&key);
+                    self.index.remove(&key);
+                }
+                None => {}
+            }
+        }
+        self.gens = self.gens.split_off(&(last_gen + 1));
+        self.gens.insert(compaction_gen, stats);
+
+        self.reader.safe_point.store(last_gen + 1, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        // the snapshots may still read the stale log files
+        self.versions.compacted(last_gen);
+        self.sealed.seal(compaction_gen);
+        self.sealed.remove_compacted(last_gen);
+        self.compacting = false;
+        if self.rekey_gen.is_some_and(|gen| gen <= last_gen) {
+            info!("{:?}: rekeying finished", self.path);
+            self.rekey_gen = None;
+        }
+    }
+
+    /// Removes the expired keys from the index.
+    ///
+    /// Nothing is written to the log, since expired records are replayed as
+    /// removals on open anyway.
+    fn reap(&mut self) {
+        let now = expiry::now_millis();
+        while let Some((expires_at, key)) = self.expiring.pop_first() {
+            if expires_at > now {
+                self.expiring.insert((expires_at, key));
+                break;
+            }
+            if let Some(entry) = self.index.get(&key) {
+                // the key may have been written again meanwhile
+                if entry.value().expires_at == Some(expires_at) {
+                    mark_stale(&mut self.gens, entry.value());
+                    self.cache.remove(&key);
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    /// Lets the next commit start another compaction after one failed.
+    fn abort_compaction(&mut self) {
+        self.compacting = false;
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if self.durability != Durability::Never && self.unsynced > 0 {
+            if let Err(e) = self.sync() {
+                error!("Failed to sync the log on close: {}", e);
+            }
+        }
+    }
+}
+
+/// Holds the directory lock of a writable store until its last handle is dropped.
+///
+/// A running compaction still deletes files, so it is waited for before the
+/// directory is unlocked.
+struct Closer {
+    writer: Arc<Mutex<KvStoreWriter>>,
+    reaper: Option<Reaper>,
+    _lock: DirLock,
+}
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        // stop the reaper first, it may start another compaction
+        self.reaper.take();
+        let compaction = self.writer.lock().unwrap().compaction.take();
+        if let Some(handle) = compaction {
+            if handle.join().is_err() {
+                error!("The compaction thread panicked");
+            }
+        }
+    }
+}
+
+/// A write waiting to be committed, together with the sender of its result.
+struct PendingWrite {
+    op: WriteOp,
+    tx: oneshot::Sender<Result<Outcome>>,
+}
+
+/// The operation of a pending write.
+enum WriteOp {
+    Command(Command),
+    // written as a single batch record
+    Batch(Vec<Command>),
+    // a batch written only if the keys read still have the same version
+    Transaction {
+        reads: Vec<(Vec<u8>, Version)>,
+        cmds: Vec<Command>,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    IncrBy {
+        key: Vec<u8>,
+        delta: i64,
+    },
+    Append {
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+    },
+    // removals which don't fail on a missing key
+    Take(Vec<u8>),
+    RemoveIfExists(Vec<u8>),
+    // fails the whole commit
+    #[cfg(test)]
+    Fail,
+}
+
+/// What a committed write reports back, depending on its `WriteOp`.
+enum Outcome {
+    Done,
+    Swapped(CompareAndSwapResult),
+    Incremented(i64),
+    Taken(Option<Vec<u8>>),
+    Removed(bool),
+}
+
+/// The latest record of each key appended in a commit, `None` for a removal.
+type Staged = HashMap<Vec<u8>, Option<Range<u64>>>;
+
+/// The records of a write appended to the active log, waiting for the flush.
+struct Appended {
+    records: Vec<(Command, Range<u64>)>,
+    // bytes of the log taken by the framing of the records
+    overhead: u64,
+    outcome: Result<Outcome>,
+}
+
+impl Appended {
+    /// A write which appended nothing.
+    fn outcome(outcome: Result<Outcome>) -> Appended {
+        Appended {
+            records: Vec::new(),
+            overhead: 0,
+            outcome,
+        }
+    }
+}
+
+/// Starts a compaction in the background if the writer finds enough stale data.
+fn compact_if_needed(writer: &Arc<Mutex<KvStoreWriter>>, guard: &mut KvStoreWriter) {
+    if let Some(compaction) = guard.start_compaction() {
+        match compaction.spawn(Arc::clone(writer)) {
+            Ok(handle) => guard.compaction = Some(handle),
+            Err(e) => {
+                error!("Failed to spawn the compaction thread: {}", e);
+                guard.abort_compaction();
+            }
+        }
+    }
+}
+
+/// Reads the value of `key` at `cmd_pos` for `get` and `scan`.
+///
+/// The value comes from the value cache if it has it, and is cached otherwise. A
+/// value of a sealed generation is read from its memory map if there is one, and
+/// every other value through a reader taken from `reader_pool`.
+fn read_value(
+    key: &[u8],
+    cmd_pos: CommandPos,
+    cache: &ValueCache,
+    sealed: &SealedLogs,
+    reader_pool: &ReaderPool,
+) -> Result<Vec<u8>> {
+    if let Some(value) = cache.get(key, &cmd_pos) {
+        return Ok(value);
+    }
+    let res = sealed
+        .read_command(cmd_pos)
+        .unwrap_or_else(|| reader_pool.get()?.read_command(cmd_pos));
+    match res? {
+        Command::Set { value, .. } => {
+            cache.insert(key.to_owned(), cmd_pos, value.clone());
+            Ok(value)
+        }
+        Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Converts the writes of `batch` to commands.
+fn batch_cmds(batch: WriteBatch) -> Vec<Command> {
+    batch
+        .into_ops()
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value } => Command::set(key, value),
+            BatchOp::Remove { key } => Command::remove(key),
+        })
+        .collect()
+}
+
+/// Extracts the result of a write which reports nothing back.
+fn done(_: Outcome) -> Result<()> {
+    Ok(())
+}
+
+fn respond<T>(tx: oneshot::Sender<Result<T>>, res: Result<T>) {
+    if tx.send(res).is_err() {
+        error!("Receiving end is dropped");
+    }
+}
+
+/// Copies an error that is reported to several writers of a group.
+fn share_error(e: &KvsError) -> KvsError {
+    match e {
+        KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+        KvsError::KeyspaceNotFound(name) => KvsError::KeyspaceNotFound(name.clone()),
+        e => KvsError::StringError(e.to_string()),
+    }
+}
+
+/// Removes the log files of the generations up to `last_gen` after a compaction.
+///
+/// Note that actually these files are not deleted immediately because `KvStoreReader`s
+/// still keep open file handles. When `KvStoreReader` is used next time, it will clear
+/// its stale file handles. On Unix, the files will be deleted after all the handles
+/// are closed. On Windows, the deletions below will fail and stale files are expected
+/// to be deleted in the next compaction.
+fn remove_compacted(path: &Path, last_gen: u64) {
+    match sorted_gen_list(path) {
+        Ok(gen_list) => {
+            for stale_gen in gen_list.into_iter().filter(|&gen| gen <= last_gen) {
+                let file_path = log_path(path, stale_gen);
+                if let Err(e) = fs::remove_file(&file_path) {
+                    error!("{:?} cannot be deleted: {}", file_path, e);
+                }
+                if let Err(e) = hint::remove_hint(path, stale_gen) {
+                    error!(
+                        "Hint file of generation {} cannot be deleted: {}",
+                        stale_gen, e
+                    );
+                }
+            }
+        }
+        Err(e) => error!("Failed to list stale log files: {}", e),
+    }
+}
+
+/// Create a new log file with given generation number and add the reader to the readers map.
+///
+/// Returns the writer to the log.
+fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(&path, gen);
+    let writer = BufWriterWithPos::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(true)
+            .open(&path)?,
+    )?;
+    Ok(writer)
+}
+
+/// Returns sorted generation numbers in the given directory.
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Size of a log file and how many of its bytes are stale.
+#[derive(Debug, Default, Clone, Copy)]
+struct GenStats {
+    len: u64,
+    stale: u64,
+}
+
+/// `GenStats` of all log files by generation.
+type GenStatsMap = BTreeMap<u64, GenStats>;
+
+/// Counts the record at `cmd_pos` as stale.
+fn mark_stale(gens: &mut GenStatsMap, cmd_pos: &CommandPos) {
+    gens.entry(cmd_pos.gen).or_default().stale += cmd_pos.len;
+}
+
+/// Load the whole log file and store value locations in the index map.
+///
+/// Replay stops at the first invalid record, whose offset is returned together with
+/// the reason, see `truncate_torn_tail`. Stale records are counted in `gens`.
+fn load(
+    gen: u64,
+    reader: &mut BufReaderWithPos<LogFile>,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    gens: &mut GenStatsMap,
+    keyring: &Keyring,
+) -> Result<Option<(u64, Corruption)>> {
+    // To make sure we read from the beginning of the file.
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut torn_at = None;
+    loop {
+        let (cmds, len) = match record::read_record(reader, keyring)? {
+            Decoded::Record(cmd, len) => (vec![(cmd, 0..len)], len),
+            Decoded::Batch(cmds, len) => {
+                // the header of a batch is dropped in the next compaction
+                gens.entry(gen).or_default().stale += record::HEADER_LEN as u64;
+                (cmds, len)
+            }
+            Decoded::Eof => break,
+            Decoded::Corrupted(reason) => {
+                torn_at = Some((pos, reason));
+                break;
+            }
+        };
+        let now = expiry::now_millis();
+        for (cmd, range) in cmds {
+            let cmd_pos: CommandPos = (gen, pos + range.start..pos + range.end).into();
+            match cmd {
+                Command::Set {
+                    key,
+                    expires_at: Some(expires_at),
+                    ..
+                } if expires_at <= now => {
+                    // an expired value removes the key like a "remove" command
+                    if let Some(old_cmd) = index.remove(&key) {
+                        mark_stale(gens, old_cmd.value());
+                    }
+                    mark_stale(gens, &cmd_pos);
+                }
+                Command::Set {
+                    key, expires_at, ..
+                } => {
+                    if let Some(old_cmd) = index.get(&key) {
+                        mark_stale(gens, old_cmd.value());
+                    }
+                    index.insert(key, cmd_pos.with_expiry(expires_at));
+                }
+                Command::Remove { key } => {
+                    if let Some(old_cmd) = index.remove(&key) {
+                        mark_stale(gens, old_cmd.value());
+                    }
+                    // the "remove" command itself can be deleted in the next compaction.
+                    // so we count it as stale.
+                    mark_stale(gens, &cmd_pos);
+                }
+            }
+        }
+        pos += len;
+    }
+    Ok(torn_at)
+}
+
+/// Drops the torn record starting at `pos` from the log file of `gen`, which is
+/// invalid because of `reason`.
+///
+/// A crash can leave the last record incomplete, or complete in length but filled
+/// with zeros or garbage. A damaged record followed by more valid records isn't torn
+/// though: the file is corrupted in the middle and nothing is truncated. A store
+/// opened read-only only checks the tail, as a writer may still be appending to it.
+fn truncate_torn_tail(
+    path: &Path,
+    gen: u64,
+    pos: u64,
+    reason: Corruption,
+    keyring: &Keyring,
+    read_only: bool,
+) -> Result<()> {
+    let path = log_path(path, gen);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(&path)?;
+    file.seek(SeekFrom::Start(pos))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    if record::contains_record(&tail[1..], keyring) {
+        return Err(reason.at(gen, pos));
+    }
+    if read_only {
+        return Ok(());
+    }
+
+    file.set_len(pos)?;
+    file.sync_all()?;
+    warn!(
+        "{:?}: dropped {} bytes of a torn record at offset {}: {}",
+        path,
+        tail.len(),
+        pos,
+        reason
+    );
+    Ok(())
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Struct representing a command.
+#[derive(Debug)]
+enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // in milliseconds since the UNIX epoch
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+impl Command {
+    fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+        }
+    }
+
+    fn expiring(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        }
+    }
+
+    fn remove(key: Vec<u8>) -> Command {
+        Command::Remove { key }
+    }
+
+    fn key(&self) -> &[u8] {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } => key,
+        }
+    }
+}
+
+/// Represents the position and length of a command record in the log,
+/// together with the expiry time of its value and the commit which wrote it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    expires_at: Option<u64>,
+    // sequence number of the commit, 0 for the records loaded on open
+    seq: u64,
+}
+
+impl CommandPos {
+    fn with_expiry(self, expires_at: Option<u64>) -> CommandPos {
+        CommandPos { expires_at, ..self }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            expires_at: None,
+            seq: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::Current(0))?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+/// A log file handle which reads at an offset of its own.
+///
+/// Duplicated handles share the offset of the file, so reading through them with
+/// seeks would race between threads.
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    pos: u64,
+}
+
+impl LogFile {
+    fn new(file: File) -> LogFile {
+        LogFile { file, pos: 0 }
+    }
+
+    fn try_clone(&self) -> io::Result<LogFile> {
+        Ok(LogFile::new(self.file.try_clone()?))
+    }
+}
+
+impl Read for LogFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let len = std::os::unix::fs::FileExt::read_at(&self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let len = std::os::windows::fs::FileExt::seek_read(&self.file, buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.file.metadata()?.len().checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::Current(0))?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Flushes the buffer and syncs the file content to disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::thread_pool::SharedQueueThreadPool;
+
+    type Store = KvStore<SharedQueueThreadPool>;
+
+    fn pending(op: WriteOp) -> (PendingWrite, oneshot::Receiver<Result<Outcome>>) {
+        let (tx, rx) = oneshot::channel();
+        (PendingWrite { op, tx }, rx)
+    }
+
+    #[tokio::test]
+    async fn failed_commit_is_rolled_back() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Store::open(temp_dir.path(), 2)?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+
+        // appending reads the staged record back, which flushes the group so far
+        let ops = vec![
+            WriteOp::Command(Command::set(b"key2".to_vec(), b"value2".to_vec())),
+            WriteOp::Append {
+                key: b"key2".to_vec(),
+                suffix: b"!".to_vec(),
+            },
+            WriteOp::Fail,
+            WriteOp::Command(Command::set(b"key3".to_vec(), b"value3".to_vec())),
+        ];
+        let (group, receivers): (Vec<_>, Vec<_>) = ops.into_iter().map(pending).unzip();
+        store.writer.as_ref().unwrap().lock().unwrap().commit(group);
+        for rx in receivers {
+            assert!(rx.await.unwrap().is_err());
+        }
+        store.set("key4".to_owned(), "value4".to_owned()).await?;
+
+        check_rolled_back(&store).await?;
+        drop(store);
+        check_rolled_back(&Store::open(temp_dir.path(), 2)?).await
+    }
+
+    #[tokio::test]
+    async fn failed_read_fails_only_its_write() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Store::open(temp_dir.path(), 2)?;
+        store.set("counter".to_owned(), "5".to_owned()).await?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+
+        // damage the value of the first record, after the 20 bytes header and the key
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(log_path(temp_dir.path(), 1))?;
+        file.seek(SeekFrom::Start(20 + 7))?;
+        file.write_all(b"X")?;
+        drop(file);
+
+        let ops = vec![
+            WriteOp::Command(Command::set(b"key2".to_vec(), b"value2".to_vec())),
+            WriteOp::IncrBy {
+                key: b"counter".to_vec(),
+                delta: 1,
+            },
+            WriteOp::Take(b"counter".to_vec()),
+            WriteOp::Command(Command::set(b"key3".to_vec(), b"value3".to_vec())),
+        ];
+        let (group, receivers): (Vec<_>, Vec<_>) = ops.into_iter().map(pending).unzip();
+        store.writer.as_ref().unwrap().lock().unwrap().commit(group);
+        let mut results = Vec::new();
+        for rx in receivers {
+            results.push(rx.await.unwrap());
+        }
+        assert!(matches!(results[0], Ok(Outcome::Done)));
+        for res in &results[1..3] {
+            assert!(matches!(res, Err(KvsError::CorruptedLog { gen: 1, .. })));
+        }
+        assert!(matches!(results[3], Ok(Outcome::Done)));
+        for key in ["key2", "key3"] {
+            let expected = format!("value{}", &key[3..]);
+            assert_eq!(store.get(key.to_owned()).await?, Some(expected));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interval_syncs_without_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .durability(Durability::Interval(Duration::from_secs(1)))
+            .reap_interval(Duration::from_secs(3600));
+        let store = Store::open_with(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        let unsynced = || store.writer.as_ref().unwrap().lock().unwrap().unsynced;
+        assert!(unsynced() > 0);
+        for _ in 0..30 {
+            if unsynced() == 0 {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        panic!("the log was not synced");
+    }
+
+    async fn check_rolled_back(store: &Store) -> Result<()> {
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).await?, None);
+        assert_eq!(store.get("key3".to_owned()).await?, None);
+        assert_eq!(
+            store.get("key4".to_owned()).await?,
+            Some("value4".to_owned())
+        );
+        Ok(())
+    }
+}
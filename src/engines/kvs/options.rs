@@ -0,0 +1,269 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use super::EncryptionKey;
+use crate::engines::expiry::DEFAULT_REAP_INTERVAL;
+
+/// When `KvStore` forces written records to disk with `fsync`.
+///
+/// Records are always flushed to the operating system before a write completes,
+/// so they survive a crash of the process. Syncing additionally protects them
+/// against a crash of the whole machine, at the cost of write throughput.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never sync explicitly and leave write back to the operating system.
+    #[default]
+    Never,
+    /// Sync after every write.
+    EveryWrite,
+    /// Sync on a write if the last sync is at least this long ago.
+    ///
+    /// Records written after the last sync are synced in the background within
+    /// the interval, also if no more writes follow.
+    Interval(Duration),
+    /// Sync once at least this many bytes were written since the last sync.
+    Bytes(u64),
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Never => write!(f, "never"),
+            Durability::EveryWrite => write!(f, "always"),
+            Durability::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+            Durability::Bytes(bytes) => write!(f, "{}b", bytes),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses `never`, `always`, an interval like `100ms` or a byte count like `4096b`.
+    ///
+    /// The interval must not be zero, `always` syncs on every write instead.
+    fn from_str(input: &str) -> std::result::Result<Durability, Self::Err> {
+        let invalid = || format!("durability: {} is not never, always, <N>ms or <N>b", input);
+        match input {
+            "never" => Ok(Durability::Never),
+            "always" => Ok(Durability::EveryWrite),
+            _ => {
+                if let Some(ms) = input.strip_suffix("ms") {
+                    let ms = ms.parse().map_err(|_| invalid())?;
+                    if ms == 0 {
+                        return Err(format!("durability: {} is not a positive interval", input));
+                    }
+                    Ok(Durability::Interval(Duration::from_millis(ms)))
+                } else if let Some(bytes) = input.strip_suffix('b') {
+                    Ok(Durability::Bytes(bytes.parse().map_err(|_| invalid())?))
+                } else {
+                    Err(invalid())
+                }
+            }
+        }
+    }
+}
+
+/// How `KvStore` compresses the values it writes.
+///
+/// Each record is marked whether its value is compressed, so stores can be
+/// reopened with another setting. Compaction rewrites the older records with the
+/// current one. Values too short to gain from compression are stored as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Store values uncompressed.
+    #[default]
+    None,
+    /// Compress values with LZ4, which is fast at a moderate ratio.
+    Lz4,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    /// Parses `none` or `lz4`.
+    fn from_str(input: &str) -> std::result::Result<Compression, Self::Err> {
+        match input {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!("compression: {} is not none or lz4", input)),
+        }
+    }
+}
+
+/// Options to open a `KvStore` with.
+///
+/// ```rust
+/// # use std::time::Duration;
+/// use kvs::{Durability, KvStoreOptions};
+/// let options = KvStoreOptions::new()
+///     .concurrency(4)
+///     .compaction_threshold(16 * 1024 * 1024)
+///     .durability(Durability::Interval(Duration::from_millis(100)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) concurrency: u32,
+    pub(super) compaction_threshold: u64,
+    pub(super) max_file_size: u64,
+    pub(super) reader_pool_size: Option<usize>,
+    pub(super) cache_size: u64,
+    pub(super) mmap_reads: bool,
+    pub(super) durability: Durability,
+    pub(super) compression: Compression,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    // `Some(None)` when rekeying a store which isn't encrypted yet
+    pub(super) rekey_from: Option<Option<EncryptionKey>>,
+    pub(super) read_only: bool,
+    pub(super) create_if_missing: bool,
+    pub(super) reap_interval: Duration,
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        KvStoreOptions {
+            concurrency: num_cpus::get() as u32,
+            compaction_threshold: 1024 * 1024,
+            max_file_size: 64 * 1024 * 1024,
+            reader_pool_size: None,
+            cache_size: 8 * 1024 * 1024,
+            mmap_reads: true,
+            durability: Durability::default(),
+            compression: Compression::default(),
+            encryption_key: None,
+            rekey_from: None,
+            read_only: false,
+            create_if_missing: true,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+        }
+    }
+
+    /// Sets how many threads at most can read the database at the same time.
+    ///
+    /// Defaults to the number of CPUs.
+    pub fn concurrency(mut self, concurrency: u32) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Sets how many stale bytes trigger a compaction.
+    ///
+    /// Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the size after which writes roll over to a new log file.
+    ///
+    /// A log file can exceed it by the records of one group commit. Defaults to 64 MiB.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Sets how many readers with their own file handles are pooled.
+    ///
+    /// More reads can run at once, each opening a reader which is closed afterwards.
+    /// Defaults to one more than the concurrency.
+    pub fn reader_pool_size(mut self, size: usize) -> Self {
+        self.reader_pool_size = Some(size);
+        self
+    }
+
+    /// Sets how many bytes of recently read values are cached in memory.
+    ///
+    /// Each keyspace has a cache of this size. Zero disables the cache. Defaults to 8 MiB.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Sets whether `get` reads sealed log files through memory maps.
+    ///
+    /// Mapped reads need no pooled reader, so they aren't limited by the reader pool
+    /// size. The active log is always read through a pooled reader. Defaults to `true`.
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Sets when written records are synced to disk.
+    ///
+    /// Defaults to `Durability::Never`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets how written values are compressed.
+    ///
+    /// Defaults to `Compression::None`.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the key to encrypt written values with.
+    ///
+    /// Every record is then authenticated on read and replay, and records which
+    /// are unencrypted or sealed with another key are rejected, unless they are being
+    /// rekeyed, see `rekey_from`. Keys aren't encrypted. Defaults to no encryption.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Rewrites the records sealed with `old_key`, or the unencrypted records if
+    /// `None`, under the current encryption key.
+    ///
+    /// Records sealed with either key are read, and all log files present on open
+    /// are compacted in the background right away. Once that compaction finished,
+    /// the store can be opened without the old key. A store without an encryption
+    /// key is decrypted this way.
+    pub fn rekey_from(mut self, old_key: Option<EncryptionKey>) -> Self {
+        self.rekey_from = Some(old_key);
+        self
+    }
+
+    /// Opens the store for reads only.
+    ///
+    /// Writes fail with `KvsError::ReadOnly`, and opening neither creates nor
+    /// modifies any file, see `KvStore::open_read_only`. Defaults to `false`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets whether a missing directory is created on open.
+    ///
+    /// Opening a missing directory fails otherwise. Defaults to `true`.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Sets how often expired keys are removed in the background.
+    ///
+    /// Expired keys are never read in between. Defaults to one second, and intervals
+    /// below a millisecond are raised to one.
+    pub fn reap_interval(mut self, interval: Duration) -> Self {
+        self.reap_interval = interval;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
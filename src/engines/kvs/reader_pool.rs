@@ -0,0 +1,128 @@
+use std::{ops::Deref, sync::Mutex};
+
+use crossbeam::queue::ArrayQueue;
+
+use super::KvStoreReader;
+use crate::{KvsError, Result};
+
+/// The `KvStoreReader`s of a store, shared by all threads reading from the log files.
+///
+/// A reader is taken out of the pool for a read and returned by dropping its
+/// `PooledReader`, also if the read fails. When all pooled readers are taken, e.g.
+/// because the thread pool runs more reads at once than the configured concurrency,
+/// a new reader is opened instead of waiting. The pool keeps at most its size of
+/// readers, so the extra ones are closed once they are returned.
+pub(super) struct ReaderPool {
+    readers: ArrayQueue<KvStoreReader>,
+    // the extra readers are opened from it
+    template: Mutex<KvStoreReader>,
+}
+
+impl ReaderPool {
+    /// Creates a pool of `size` readers opened from `reader`.
+    ///
+    /// Without a writer, nothing closes handles of deleted files, so the readers of a
+    /// read-only store keep the files of the loaded index open.
+    pub(super) fn new(reader: KvStoreReader, size: usize, read_only: bool) -> Result<ReaderPool> {
+        let size = size.max(1);
+        let template = if read_only {
+            reader.open_all()?
+        } else {
+            reader.clone()
+        };
+        let readers = ArrayQueue::new(size);
+        for _ in 1..size {
+            if readers.push(template.open_all()?).is_err() {
+                return Err(KvsError::StringError(
+                    "push reader to pool error".to_owned(),
+                ));
+            }
+        }
+        if readers.push(reader).is_err() {
+            return Err(KvsError::StringError(
+                "push reader to pool error".to_owned(),
+            ));
+        }
+        Ok(ReaderPool {
+            readers,
+            template: Mutex::new(template),
+        })
+    }
+
+    /// Takes a reader out of the pool, or opens a new one if none is left.
+    pub(super) fn get(&self) -> Result<PooledReader<'_>> {
+        let reader = match self.readers.pop() {
+            Some(reader) => reader,
+            None => self.template.lock().unwrap().open_all()?,
+        };
+        Ok(PooledReader {
+            pool: self,
+            reader: Some(reader),
+        })
+    }
+}
+
+/// A reader taken out of a `ReaderPool`, which is returned when it is dropped.
+pub(super) struct PooledReader<'a> {
+    pool: &'a ReaderPool,
+    // `None` once returned
+    reader: Option<KvStoreReader>,
+}
+
+impl Deref for PooledReader<'_> {
+    type Target = KvStoreReader;
+
+    fn deref(&self) -> &KvStoreReader {
+        self.reader.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledReader<'_> {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            // the pool is only full if this is an extra reader, which is closed
+            let _ = self.pool.readers.push(reader);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{atomic::AtomicU64, Arc},
+    };
+
+    use super::*;
+    use crate::engines::kvs::{cipher::Keyring, KvStoreOptions};
+
+    fn pool(size: usize) -> ReaderPool {
+        let reader = KvStoreReader {
+            path: Arc::new(PathBuf::new()),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            keyring: Arc::new(Keyring::new(&KvStoreOptions::new())),
+            readers: Default::default(),
+        };
+        ReaderPool::new(reader, size, false).unwrap()
+    }
+
+    #[test]
+    fn opens_extra_readers() {
+        let pool = pool(2);
+        let taken: Vec<_> = (0..5).map(|_| pool.get().unwrap()).collect();
+        assert!(pool.readers.is_empty());
+        drop(taken);
+        assert_eq!(pool.readers.len(), 2);
+    }
+
+    #[test]
+    fn returns_readers_on_panic() {
+        let pool = pool(1);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _reader = pool.get().unwrap();
+            panic!("read failed");
+        }));
+        assert!(res.is_err());
+        assert_eq!(pool.readers.len(), 1);
+    }
+}
@@ -0,0 +1,724 @@
+//! Binary record format of the `KvStore` log files.
+//!
+//! Every command is stored as a fixed size header followed by the key and the value:
+//!
+//! ```text
+//! 0       4       8         9      10      12        16          20
+//! +-------+-------+---------+------+-------+---------+-----------+-----+-------+
+//! | magic | crc32 | version | kind | flags | key_len | value_len | key | value |
+//! +-------+-------+---------+------+-------+---------+-----------+-----+-------+
+//! ```
+//!
+//! All integers are little endian. The checksum covers everything after the `crc32`
+//! field, so a flipped bit in the lengths is detected as well as one in the payload.
+//!
+//! A value which expires is prefixed with its expiry time in milliseconds since
+//! the UNIX epoch as a `u64`, and the record has the `expires` flag set.
+//!
+//! A compressed value has the `lz4` flag set. It holds the length of the original
+//! value as a `u32` followed by the LZ4 block, and comes after the expiry time if
+//! there is one. The checksum covers the compressed bytes.
+//!
+//! An encrypted record has the `encrypted` flag set and its value sealed as described
+//! in `cipher`. "Remove" records get a sealed empty value, so that every record of an
+//! encrypted store is authenticated. The checksum covers the sealed bytes.
+//!
+//! A write batch is a single record of the `batch` kind with an empty key. Its value
+//! holds the records of the batched commands, each marked with the `nested` flag.
+//! The batch checksum covers all of them, so a batch is either replayed completely
+//! or detected as corrupted. The index points at the nested records, which can be
+//! read like any other record.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    ops::Range,
+};
+
+use super::{
+    cipher::{Keyring, SEAL_OVERHEAD},
+    Command, Compression,
+};
+use crate::{KvsError, Result};
+
+/// Marks the beginning of every record.
+const MAGIC: u32 = 0x5253_564B; // "KVSR"
+/// Version of the record layout written by this build.
+const VERSION: u8 = 1;
+/// Length of the record header in bytes.
+pub(super) const HEADER_LEN: usize = 20;
+
+const KIND_SET: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+const KIND_BATCH: u8 = 3;
+
+/// Set on the records nested in a batch.
+const FLAG_NESTED: u16 = 0x0001;
+/// Set on the records of values which expire.
+const FLAG_EXPIRES: u16 = 0x0002;
+/// Set on the records of values compressed with LZ4.
+const FLAG_LZ4: u16 = 0x0004;
+/// Set on the records whose value is sealed with an encryption key.
+const FLAG_ENCRYPTED: u16 = 0x0008;
+/// Length of the expiry time in front of an expiring value.
+const EXPIRY_LEN: usize = 8;
+/// Values shorter than this are never compressed.
+const MIN_COMPRESSED_LEN: usize = 64;
+
+/// Reason why a record cannot be decoded.
+#[derive(Debug)]
+pub(super) enum Corruption {
+    /// The record ends before its header or payload is complete.
+    Truncated,
+    /// The record does not start with the magic number.
+    BadMagic(u32),
+    /// The record was written by an unknown version of the format.
+    UnsupportedVersion(u8),
+    /// The record has an unknown command kind.
+    UnknownKind(u8),
+    /// A record nested in a batch is invalid.
+    InvalidBatch,
+    /// The value of an expiring record is too short to hold the expiry time.
+    MissingExpiry,
+    /// The compressed value of a record cannot be decompressed.
+    BadCompression,
+    /// The record is sealed with a key the store isn't opened with.
+    UnknownKey(u64),
+    /// The sealed value of a record fails authentication.
+    Unauthenticated,
+    /// The record isn't encrypted although the store is.
+    Unencrypted,
+    /// The stored checksum doesn't match the record content.
+    ChecksumMismatch {
+        /// Checksum stored in the header.
+        expected: u32,
+        /// Checksum computed from the record.
+        actual: u32,
+    },
+}
+
+impl Corruption {
+    /// Returns whether the corruption can be left by a write interrupted by a crash,
+    /// which leaves a short record, zeros or garbage behind.
+    ///
+    /// A record with a valid checksum was written completely, so anything wrong with
+    /// it, like being sealed with another key, is never mistaken for a torn write.
+    pub(super) fn is_torn_write(&self) -> bool {
+        matches!(
+            self,
+            Corruption::Truncated | Corruption::BadMagic(_) | Corruption::ChecksumMismatch { .. }
+        )
+    }
+
+    /// Converts the corruption into an error pointing at the record location.
+    pub(super) fn at(self, gen: u64, offset: u64) -> KvsError {
+        KvsError::CorruptedLog {
+            gen,
+            offset,
+            reason: self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::Truncated => write!(f, "truncated record"),
+            Corruption::BadMagic(magic) => write!(f, "bad magic number {:#010x}", magic),
+            Corruption::UnsupportedVersion(version) => {
+                write!(f, "unsupported record version {}", version)
+            }
+            Corruption::UnknownKind(kind) => write!(f, "unknown record kind {}", kind),
+            Corruption::InvalidBatch => write!(f, "invalid record in batch"),
+            Corruption::MissingExpiry => write!(f, "missing expiry time"),
+            Corruption::BadCompression => write!(f, "bad compressed value"),
+            Corruption::UnknownKey(id) => write!(f, "sealed with unknown key {:016x}", id),
+            Corruption::Unauthenticated => write!(f, "authentication failed"),
+            Corruption::Unencrypted => write!(f, "unencrypted record"),
+            Corruption::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch (expected {:#010x}, got {:#010x})",
+                expected, actual
+            ),
+        }
+    }
+}
+
+/// Outcome of decoding a record from a stream.
+pub(super) enum Decoded {
+    /// A complete and valid record, together with its length in bytes.
+    Record(Command, u64),
+    /// A complete and valid batch, together with its length in bytes. Each command
+    /// comes with the range of its nested record relative to the batch start.
+    Batch(Vec<(Command, Range<u64>)>, u64),
+    /// The stream ended exactly at a record boundary.
+    Eof,
+    /// The record at the current position is invalid.
+    Corrupted(Corruption),
+}
+
+/// Serializes `cmd` into `writer`, compressing its value with `compression` and
+/// sealing it with the current key of `keyring`.
+///
+/// Returns the number of bytes written. Nothing is written if the record can't be
+/// encoded, see `encode_record`.
+pub(super) fn write_record<W: Write>(
+    writer: &mut W,
+    cmd: &Command,
+    compression: Compression,
+    keyring: &Keyring,
+) -> Result<u64> {
+    let buf = encode_record(cmd, compression, keyring)?;
+    writer.write_all(&buf)?;
+    Ok(buf.len() as u64)
+}
+
+/// Encodes `cmd` as a record like `write_record` does.
+///
+/// # Errors
+///
+/// It returns `KvsError::StringError` if the key or value is too large for the
+/// record header.
+pub(super) fn encode_record(
+    cmd: &Command,
+    compression: Compression,
+    keyring: &Keyring,
+) -> Result<Vec<u8>> {
+    encode(cmd, 0, compression, keyring)
+}
+
+/// Encodes `cmds` as a single batch record.
+///
+/// Returns the record and the range of each nested record relative to its start.
+///
+/// # Errors
+///
+/// It returns `KvsError::StringError` if a key or value, or the whole batch, is too
+/// large for the record header.
+pub(super) fn encode_batch(
+    cmds: &[Command],
+    compression: Compression,
+    keyring: &Keyring,
+) -> Result<(Vec<u8>, Vec<Range<u64>>)> {
+    let mut payload = Vec::new();
+    let mut ranges = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let start = (HEADER_LEN + payload.len()) as u64;
+        payload.extend(encode(cmd, FLAG_NESTED, compression, keyring)?);
+        ranges.push(start..(HEADER_LEN + payload.len()) as u64);
+    }
+    // the nested records are sealed one by one, so that they can be read on their own
+    let buf = frame(KIND_BATCH, 0, &[], &payload, None)?;
+    Ok((buf, ranges))
+}
+
+/// Reads one record from `reader`, opening sealed values with `keyring`.
+///
+/// I/O errors are propagated. Malformed data is reported as `Decoded::Corrupted`
+/// so that the caller can attach the location of the record.
+pub(super) fn read_record<R: Read>(reader: &mut R, keyring: &Keyring) -> Result<Decoded> {
+    let mut header = [0u8; HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(Decoded::Eof),
+        n if n < HEADER_LEN => return Ok(Decoded::Corrupted(Corruption::Truncated)),
+        _ => {}
+    }
+
+    let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    if magic != MAGIC {
+        return Ok(Decoded::Corrupted(Corruption::BadMagic(magic)));
+    }
+    let expected = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let version = header[8];
+    if version != VERSION {
+        return Ok(Decoded::Corrupted(Corruption::UnsupportedVersion(version)));
+    }
+    let key_len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+
+    // Read through `take` so that a corrupted length can't make us allocate
+    // more than what is actually left in the file.
+    let mut payload = Vec::new();
+    reader
+        .take((key_len + value_len) as u64)
+        .read_to_end(&mut payload)?;
+    if payload.len() < key_len + value_len {
+        return Ok(Decoded::Corrupted(Corruption::Truncated));
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[8..]);
+    hasher.update(&payload);
+    let actual = hasher.finalize();
+    if actual != expected {
+        return Ok(Decoded::Corrupted(Corruption::ChecksumMismatch {
+            expected,
+            actual,
+        }));
+    }
+
+    let len = (HEADER_LEN + key_len + value_len) as u64;
+    let flags = u16::from_le_bytes(header[10..12].try_into().unwrap());
+    let mut value = payload.split_off(key_len);
+    let key = payload;
+    if flags & FLAG_ENCRYPTED != 0 {
+        let mut aad = header[8..].to_vec();
+        aad.extend_from_slice(&key);
+        value = match keyring.open(&aad, &value) {
+            Ok(value) => value,
+            Err(reason) => return Ok(Decoded::Corrupted(reason)),
+        };
+    } else if header[9] != KIND_BATCH && !keyring.accepts_plaintext() {
+        return Ok(Decoded::Corrupted(Corruption::Unencrypted));
+    }
+    let cmd = match header[9] {
+        KIND_SET => {
+            let expires_at = if flags & FLAG_EXPIRES != 0 {
+                if value.len() < EXPIRY_LEN {
+                    return Ok(Decoded::Corrupted(Corruption::MissingExpiry));
+                }
+                let expires_at = u64::from_le_bytes(value[..EXPIRY_LEN].try_into().unwrap());
+                value.drain(..EXPIRY_LEN);
+                Some(expires_at)
+            } else {
+                None
+            };
+            if flags & FLAG_LZ4 != 0 {
+                value = match lz4_flex::decompress_size_prepended(&value) {
+                    Ok(value) => value,
+                    Err(_) => return Ok(Decoded::Corrupted(Corruption::BadCompression)),
+                };
+            }
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            }
+        }
+        KIND_REMOVE => Command::Remove { key },
+        KIND_BATCH => {
+            return Ok(match decode_batch(&value, keyring) {
+                Some(cmds) => Decoded::Batch(cmds, len),
+                None => Decoded::Corrupted(Corruption::InvalidBatch),
+            })
+        }
+        kind => return Ok(Decoded::Corrupted(Corruption::UnknownKind(kind))),
+    };
+    Ok(Decoded::Record(cmd, len))
+}
+
+/// Decodes the nested records of a batch, or returns `None` if any is invalid.
+fn decode_batch(mut payload: &[u8], keyring: &Keyring) -> Option<Vec<(Command, Range<u64>)>> {
+    let mut cmds = Vec::new();
+    let mut pos = HEADER_LEN as u64;
+    while !payload.is_empty() {
+        match read_record(&mut payload, keyring) {
+            Ok(Decoded::Record(cmd, len)) => {
+                cmds.push((cmd, pos..pos + len));
+                pos += len;
+            }
+            _ => return None,
+        }
+    }
+    Some(cmds)
+}
+
+/// Returns whether a valid record starts anywhere in `bytes`.
+///
+/// Records nested in a batch don't count, since they are also found in the
+/// partially written batch they belong to.
+pub(super) fn contains_record(bytes: &[u8], keyring: &Keyring) -> bool {
+    let magic = MAGIC.to_le_bytes();
+    bytes
+        .windows(magic.len())
+        .enumerate()
+        .filter(|(_, window)| *window == magic)
+        .any(|(i, _)| match read_record(&mut &bytes[i..], keyring) {
+            Ok(Decoded::Record(..)) => {
+                let flags = u16::from_le_bytes(bytes[i + 10..i + 12].try_into().unwrap());
+                flags & FLAG_NESTED == 0
+            }
+            Ok(Decoded::Batch(..)) => true,
+            _ => false,
+        })
+}
+
+fn encode(
+    cmd: &Command,
+    mut flags: u16,
+    compression: Compression,
+    keyring: &Keyring,
+) -> Result<Vec<u8>> {
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        } => {
+            // the size of a compressed value is stored in as many bytes as its length
+            len_field(value.len())?;
+            let compressed = compress(value, compression);
+            if compressed.is_some() {
+                flags |= FLAG_LZ4;
+            }
+            let value = compressed.as_deref().unwrap_or(value);
+            match expires_at {
+                Some(expires_at) => {
+                    let mut expiring = Vec::with_capacity(EXPIRY_LEN + value.len());
+                    expiring.extend_from_slice(&expires_at.to_le_bytes());
+                    expiring.extend_from_slice(value);
+                    frame(
+                        KIND_SET,
+                        flags | FLAG_EXPIRES,
+                        key,
+                        &expiring,
+                        Some(keyring),
+                    )
+                }
+                None => frame(KIND_SET, flags, key, value, Some(keyring)),
+            }
+        }
+        Command::Remove { key } => frame(KIND_REMOVE, flags, key, &[], Some(keyring)),
+    }
+}
+
+/// Compresses `value`, or returns `None` if it should be stored as it is.
+fn compress(value: &[u8], compression: Compression) -> Option<Vec<u8>> {
+    match compression {
+        Compression::None => None,
+        Compression::Lz4 if value.len() < MIN_COMPRESSED_LEN => None,
+        Compression::Lz4 => {
+            let compressed = lz4_flex::compress_prepend_size(value);
+            // incompressible values only grow
+            (compressed.len() < value.len()).then_some(compressed)
+        }
+    }
+}
+
+/// Builds a record, sealing its value if `keyring` encrypts.
+///
+/// It fails before building anything if the lengths don't fit the header.
+fn frame(
+    kind: u8,
+    mut flags: u16,
+    key: &[u8],
+    value: &[u8],
+    keyring: Option<&Keyring>,
+) -> Result<Vec<u8>> {
+    let keyring = keyring.filter(|keyring| keyring.encrypts());
+    let mut value_len = value.len();
+    if keyring.is_some() {
+        flags |= FLAG_ENCRYPTED;
+        value_len += SEAL_OVERHEAD;
+    }
+    let key_field = len_field(key.len())?;
+    let value_field = len_field(value_len)?;
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value_len);
+    buf.extend_from_slice(&MAGIC.to_le_bytes());
+    buf.extend_from_slice(&[0; 4]); // checksum, filled in below
+    buf.push(VERSION);
+    buf.push(kind);
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&key_field.to_le_bytes());
+    buf.extend_from_slice(&value_field.to_le_bytes());
+    buf.extend_from_slice(key);
+    match keyring {
+        // the header after the checksum and the key are authenticated with the value
+        Some(keyring) => {
+            let sealed = keyring.seal(&buf[8..], value);
+            buf.extend_from_slice(&sealed);
+        }
+        None => buf.extend_from_slice(value),
+    }
+
+    let crc = crc32fast::hash(&buf[8..]);
+    buf[4..8].copy_from_slice(&crc.to_le_bytes());
+    Ok(buf)
+}
+
+/// Converts the length of a key or value to its header field.
+fn len_field(len: usize) -> Result<u32> {
+    u32::try_from(len)
+        .map_err(|_| KvsError::StringError(format!("record too large: {} bytes", len)))
+}
+
+/// Fills `buf` as far as possible and returns how many bytes were read.
+///
+/// Unlike `read_exact` this tells a clean end of stream apart from a partial read.
+pub(super) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncryptionKey, KvStoreOptions};
+
+    fn plain() -> Keyring {
+        Keyring::new(&KvStoreOptions::new())
+    }
+
+    fn decode(bytes: &[u8]) -> Decoded {
+        read_record(&mut &bytes[..], &plain()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+        let len = write_record(
+            &mut buf,
+            &Command::set(b"key".to_vec(), b"value".to_vec()),
+            Compression::None,
+            &plain(),
+        )
+        .unwrap();
+        write_record(
+            &mut buf,
+            &Command::remove(b"key".to_vec()),
+            Compression::None,
+            &plain(),
+        )
+        .unwrap();
+        assert_eq!(len as usize, HEADER_LEN + 8);
+
+        let mut reader = &buf[..];
+        match read_record(&mut reader, &plain()).unwrap() {
+            Decoded::Record(Command::Set { key, value, .. }, n) => {
+                assert_eq!((&key[..], &value[..], n), (&b"key"[..], &b"value"[..], len));
+            }
+            _ => panic!("expected a set record"),
+        }
+        match read_record(&mut reader, &plain()).unwrap() {
+            Decoded::Record(Command::Remove { key }, _) => assert_eq!(key, b"key"),
+            _ => panic!("expected a remove record"),
+        }
+        assert!(matches!(
+            read_record(&mut reader, &plain()).unwrap(),
+            Decoded::Eof
+        ));
+    }
+
+    #[test]
+    fn detects_flipped_bit() {
+        let buf = encode(
+            &Command::set(b"key".to_vec(), b"value".to_vec()),
+            0,
+            Compression::None,
+            &plain(),
+        )
+        .unwrap();
+        for i in 4..buf.len() {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x01;
+            assert!(
+                matches!(decode(&corrupted), Decoded::Corrupted(_)),
+                "flipped bit in byte {} was not detected",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_lengths() {
+        assert_eq!(len_field(u32::MAX as usize).unwrap(), u32::MAX);
+        assert!(len_field(u32::MAX as usize + 1).is_err());
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let buf = encode(
+            &Command::set(b"key".to_vec(), b"value".to_vec()),
+            0,
+            Compression::None,
+            &plain(),
+        )
+        .unwrap();
+        for len in 1..buf.len() {
+            assert!(matches!(
+                decode(&buf[..len]),
+                Decoded::Corrupted(Corruption::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn finds_record_after_garbage() {
+        let mut buf = b"garbage".to_vec();
+        assert!(!contains_record(&buf, &plain()));
+        buf.extend(
+            encode(
+                &Command::remove(b"key".to_vec()),
+                0,
+                Compression::None,
+                &plain(),
+            )
+            .unwrap(),
+        );
+        assert!(contains_record(&buf, &plain()));
+        buf.pop();
+        assert!(!contains_record(&buf, &plain()));
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let cmds = vec![
+            Command::set(b"a".to_vec(), b"1".to_vec()),
+            Command::remove(b"b".to_vec()),
+        ];
+        let (buf, ranges) = encode_batch(&cmds, Compression::None, &plain()).unwrap();
+        let len = buf.len() as u64;
+
+        match decode(&buf) {
+            Decoded::Batch(decoded, n) => {
+                assert_eq!(n, len);
+                let decoded_ranges: Vec<_> = decoded.iter().map(|(_, r)| r.clone()).collect();
+                assert_eq!(decoded_ranges, ranges);
+            }
+            _ => panic!("expected a batch record"),
+        }
+        // nested records can be read on their own
+        let nested = &buf[ranges[0].start as usize..ranges[0].end as usize];
+        match decode(nested) {
+            Decoded::Record(Command::Set { key, value, .. }, _) => {
+                assert_eq!((&key[..], &value[..]), (&b"a"[..], &b"1"[..]))
+            }
+            _ => panic!("expected a set record"),
+        }
+        // but don't count as records following a torn batch
+        assert!(!contains_record(&buf[..buf.len() - 1], &plain()));
+        assert!(contains_record(&buf, &plain()));
+    }
+
+    #[test]
+    fn expiry_round_trip() {
+        let buf = encode(
+            &Command::expiring(b"key".to_vec(), b"value".to_vec(), 42),
+            0,
+            Compression::None,
+            &plain(),
+        )
+        .unwrap();
+        match decode(&buf) {
+            Decoded::Record(
+                Command::Set {
+                    value, expires_at, ..
+                },
+                _,
+            ) => assert_eq!((&value[..], expires_at), (&b"value"[..], Some(42))),
+            _ => panic!("expected a set record"),
+        }
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let value = b"a highly compressible value ".repeat(10);
+        for cmd in [
+            Command::set(b"key".to_vec(), value.clone()),
+            Command::expiring(b"key".to_vec(), value.clone(), 42),
+        ] {
+            let buf = encode(&cmd, 0, Compression::Lz4, &plain()).unwrap();
+            assert!(buf.len() < HEADER_LEN + value.len());
+            match decode(&buf) {
+                Decoded::Record(Command::Set { value: decoded, .. }, _) => {
+                    assert_eq!(decoded, value)
+                }
+                _ => panic!("expected a set record"),
+            }
+        }
+        // short and incompressible values are stored as they are
+        let buf = encode(
+            &Command::set(b"key".to_vec(), b"value".to_vec()),
+            0,
+            Compression::Lz4,
+            &plain(),
+        )
+        .unwrap();
+        assert_eq!(
+            buf,
+            encode(
+                &Command::set(b"key".to_vec(), b"value".to_vec()),
+                0,
+                Compression::None,
+                &plain()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let keyring = |key: u8| {
+            Keyring::new(&KvStoreOptions::new().encryption_key(EncryptionKey::new([key; 32])))
+        };
+        let buf = encode(
+            &Command::expiring(b"key".to_vec(), b"secret value".to_vec(), 42),
+            0,
+            Compression::None,
+            &keyring(1),
+        )
+        .unwrap();
+        assert!(!buf.windows(6).any(|window| window == b"secret"));
+        match read_record(&mut &buf[..], &keyring(1)).unwrap() {
+            Decoded::Record(
+                Command::Set {
+                    value, expires_at, ..
+                },
+                _,
+            ) => assert_eq!((&value[..], expires_at), (&b"secret value"[..], Some(42))),
+            _ => panic!("expected a set record"),
+        }
+        for keyring in [plain(), keyring(2)] {
+            assert!(matches!(
+                read_record(&mut &buf[..], &keyring).unwrap(),
+                Decoded::Corrupted(Corruption::UnknownKey(_))
+            ));
+        }
+
+        // tampering is detected even with a valid checksum
+        for i in 8..buf.len() {
+            let mut tampered = buf.clone();
+            tampered[i] ^= 0x01;
+            let crc = crc32fast::hash(&tampered[8..]);
+            tampered[4..8].copy_from_slice(&crc.to_le_bytes());
+            assert!(
+                matches!(
+                    read_record(&mut &tampered[..], &keyring(1)).unwrap(),
+                    Decoded::Corrupted(_)
+                ),
+                "tampered byte {} was not detected",
+                i
+            );
+        }
+
+        // unencrypted records are only read while rekeying
+        let unencrypted = encode(
+            &Command::remove(b"key".to_vec()),
+            0,
+            Compression::None,
+            &plain(),
+        )
+        .unwrap();
+        assert!(matches!(
+            read_record(&mut &unencrypted[..], &keyring(1)).unwrap(),
+            Decoded::Corrupted(Corruption::Unencrypted)
+        ));
+        let rekeying = Keyring::new(
+            &KvStoreOptions::new()
+                .encryption_key(EncryptionKey::new([1; 32]))
+                .rekey_from(None),
+        );
+        assert!(matches!(
+            read_record(&mut &unencrypted[..], &rekeying).unwrap(),
+            Decoded::Record(Command::Remove { .. }, _)
+        ));
+    }
+}
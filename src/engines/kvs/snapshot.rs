@@ -0,0 +1,323 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crossbeam_skiplist::SkipMap;
+use log::error;
+use tokio::sync::oneshot;
+
+use super::{
+    reader_pool::ReaderPool, remove_compacted, Closer, Command, CommandPos, KvStoreReader,
+};
+use crate::{
+    engines::{expiry, key_range, KeyRange, KvPair, KvsSnapshot},
+    thread_pool::ThreadPool,
+    KvsError, Result,
+};
+
+/// Superseded versions of the data, kept for the snapshots still held.
+///
+/// Every commit gets a sequence number, which the index positions it writes
+/// carry. A snapshot reads the data as of the sequence number it pinned. While
+/// any snapshot is held, the writer keeps every position it overwrites or removes
+/// in `history`, under the key and the sequence number of the commit which
+/// superseded it.
+///
+/// The log files of compacted generations may still hold such positions, so
+/// their deletion is delayed until the snapshots older than the compaction
+/// are released.
+pub(super) struct Versions {
+    path: Arc<PathBuf>,
+    // sequence number of the last commit applied to the index
+    committed: AtomicU64,
+    history: SkipMap<(Vec<u8>, u64), CommandPos>,
+    pins: Mutex<Pins>,
+}
+
+#[derive(Default)]
+struct Pins {
+    // number of snapshots held by sequence number
+    seqs: BTreeMap<u64, usize>,
+    // the last generation of a compaction, deleted once no snapshot older than
+    // the sequence number is held
+    doomed: Vec<(u64, u64)>,
+}
+
+impl Pins {
+    fn oldest(&self) -> Option<u64> {
+        self.seqs.keys().next().copied()
+    }
+}
+
+impl Versions {
+    pub(super) fn new(path: Arc<PathBuf>) -> Versions {
+        Versions {
+            path,
+            committed: AtomicU64::new(0),
+            history: SkipMap::new(),
+            pins: Mutex::new(Pins::default()),
+        }
+    }
+
+    /// Runs `apply` to update the index with the records of a commit.
+    ///
+    /// It gets the sequence number of the commit and whether superseded positions
+    /// must be kept. New snapshots see either all updates of the commit or none.
+    pub(super) fn commit(&self, apply: impl FnOnce(u64, bool)) {
+        let pins = self.pins.lock().unwrap();
+        let seq = self.committed.load(Ordering::SeqCst) + 1;
+        apply(seq, !pins.seqs.is_empty());
+        self.committed.store(seq, Ordering::SeqCst);
+    }
+
+    /// Keeps `old_pos` of `key`, which is superseded by the commit `seq`.
+    ///
+    /// Must be called before the index is updated.
+    pub(super) fn retain(&self, key: &[u8], seq: u64, old_pos: CommandPos) {
+        // a position written by the same commit was never visible to a snapshot
+        if old_pos.seq < seq {
+            self.history.insert((key.to_vec(), seq), old_pos);
+        }
+    }
+
+    /// Deletes the log files of the generations up to `last_gen` once no snapshot
+    /// needs them anymore.
+    pub(super) fn compacted(&self, last_gen: u64) {
+        let mut pins = self.pins.lock().unwrap();
+        if pins.seqs.is_empty() {
+            drop(pins);
+            remove_compacted(&self.path, last_gen);
+        } else {
+            let seq = self.committed.load(Ordering::SeqCst);
+            pins.doomed.push((last_gen, seq));
+        }
+    }
+
+    /// Pins the data as of the last commit and returns its sequence number.
+    fn pin(&self) -> u64 {
+        let mut pins = self.pins.lock().unwrap();
+        let seq = self.committed.load(Ordering::SeqCst);
+        *pins.seqs.entry(seq).or_default() += 1;
+        seq
+    }
+
+    /// Releases a snapshot of `seq` and drops the versions no snapshot needs anymore.
+    fn release(&self, seq: u64) {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(count) = pins.seqs.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                pins.seqs.remove(&seq);
+            }
+        }
+
+        // The snapshots look up versions superseded after their sequence number.
+        let oldest = pins.oldest();
+        for entry in self.history.iter() {
+            if oldest.is_none_or(|oldest| entry.key().1 <= oldest) {
+                entry.remove();
+            }
+        }
+
+        let (deletable, doomed) = pins
+            .doomed
+            .drain(..)
+            .partition::<Vec<_>, _>(|&(_, seq)| oldest.is_none_or(|oldest| oldest >= seq));
+        pins.doomed = doomed;
+        drop(pins);
+        if let Some(last_gen) = deletable.into_iter().map(|(gen, _)| gen).max() {
+            remove_compacted(&self.path, last_gen);
+        }
+    }
+
+    /// Returns the position of the value of `key` as of `seq`.
+    fn lookup(
+        &self,
+        index: &SkipMap<Vec<u8>, CommandPos>,
+        key: &[u8],
+        seq: u64,
+    ) -> Option<CommandPos> {
+        // The index is read first. The writer keeps a position before it replaces
+        // it, so a newer position found here is always in the history by now.
+        let current = index.get(key).map(|entry| *entry.value());
+        let superseded = self
+            .history
+            .range((key.to_vec(), seq + 1)..)
+            .next()
+            .filter(|entry| entry.key().0 == key)
+            .map(|entry| *entry.value());
+        match superseded {
+            // the first version superseded after `seq` is the one visible at `seq`,
+            // unless the key didn't exist at `seq` yet
+            Some(old_pos) => Some(old_pos).filter(|pos| pos.seq <= seq),
+            None => current.filter(|pos| pos.seq <= seq),
+        }
+    }
+
+    /// Returns the keys in `range` with a version superseded after `seq`.
+    fn superseded_keys(&self, range: &KeyRange, seq: u64) -> BTreeSet<Vec<u8>> {
+        let start = match &range.0 {
+            Bound::Included(key) => Bound::Included((key.clone(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match &range.1 {
+            Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.history
+            .range((start, end))
+            .filter(|entry| entry.key().1 > seq)
+            .map(|entry| entry.key().0.clone())
+            .collect()
+    }
+}
+
+/// A snapshot of a `KvStore`, see `KvsEngine::snapshot`.
+///
+/// Holding a snapshot keeps the overwritten values it can still read in the log,
+/// so long-lived snapshots delay reclaiming disk space.
+#[derive(Clone)]
+pub struct KvStoreSnapshot<P: ThreadPool> {
+    seq: u64,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    versions: Arc<Versions>,
+    thread_pool: P,
+    reader_pool: Arc<ReaderPool>,
+    // released before the store may be closed
+    _pinned: Arc<Pinned>,
+    _closer: Option<Arc<Closer>>,
+}
+
+impl<P: ThreadPool> KvStoreSnapshot<P> {
+    pub(super) fn new(
+        index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+        versions: Arc<Versions>,
+        thread_pool: P,
+        reader_pool: Arc<ReaderPool>,
+        closer: Option<Arc<Closer>>,
+    ) -> KvStoreSnapshot<P> {
+        let seq = versions.pin();
+        let pinned = Pinned {
+            versions: Arc::clone(&versions),
+            seq,
+        };
+        KvStoreSnapshot {
+            seq,
+            index,
+            versions,
+            thread_pool,
+            reader_pool,
+            _pinned: Arc::new(pinned),
+            _closer: closer,
+        }
+    }
+
+    /// Runs `job` on the thread pool with a reader from the pool.
+    fn spawn<T, F>(&self, job: F) -> Pin<Box<dyn Future<Output = Result<T>> + Send>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self, &KvStoreReader) -> Result<T> + Send + 'static,
+    {
+        let snapshot = self.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = snapshot
+                .reader_pool
+                .get()
+                .and_then(|reader| job(&snapshot, &reader));
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        let fut = async move {
+            match rx.await {
+                Ok(ret) => ret,
+                Err(_e) => Err(KvsError::StringError("tokio recv error".to_owned())),
+            }
+        };
+        Box::pin(fut)
+    }
+
+    /// Reads the value of `key` as of the snapshot unless it expired.
+    fn read(&self, reader: &KvStoreReader, key: &[u8], now: u64) -> Result<Option<Vec<u8>>> {
+        let cmd_pos = self
+            .versions
+            .lookup(&self.index, key, self.seq)
+            .filter(|cmd_pos| !cmd_pos.is_expired(now));
+        match cmd_pos {
+            Some(cmd_pos) => match reader.read_command(cmd_pos)? {
+                Command::Set { value, .. } => Ok(Some(value)),
+                Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl<P: ThreadPool> KvsSnapshot for KvStoreSnapshot<P> {
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>> {
+        self.spawn(move |snapshot, reader| snapshot.read(reader, &key, expiry::now_millis()))
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>> {
+        let range = key_range(&range);
+        self.spawn(move |snapshot, reader| {
+            let now = expiry::now_millis();
+            // keys removed since the snapshot are only found in the history
+            let mut superseded = snapshot.versions.superseded_keys(&range, snapshot.seq);
+            let mut current = snapshot
+                .index
+                .range(range)
+                .map(|entry| entry.key().clone())
+                .peekable();
+            let mut pairs = Vec::new();
+            while pairs.len() < limit {
+                let key = match (current.peek(), superseded.first()) {
+                    (Some(key), Some(old_key)) if old_key <= key => {
+                        let old_key = superseded.pop_first().unwrap();
+                        if old_key == *key {
+                            current.next();
+                        }
+                        old_key
+                    }
+                    (Some(_), _) => current.next().unwrap(),
+                    (None, Some(_)) => superseded.pop_first().unwrap(),
+                    (None, None) => break,
+                };
+                if let Some(value) = snapshot.read(reader, &key, now)? {
+                    pairs.push((key, value));
+                }
+            }
+            Ok(pairs)
+        })
+    }
+}
+
+/// Releases the sequence number of a snapshot once all its clones are dropped.
+struct Pinned {
+    versions: Arc<Versions>,
+    seq: u64,
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        self.versions.release(self.seq);
+    }
+}
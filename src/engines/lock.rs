@@ -0,0 +1,45 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+
+use crate::{KvsError, Result};
+
+/// An exclusive advisory lock on a data directory.
+///
+/// The lock is taken with `flock` on a `LOCK` file in the directory and released
+/// when the `DirLock` is dropped, or when the process exits.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks the directory at `dir`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if the directory is already locked, also
+    /// by another store in this process.
+    pub(crate) fn acquire(dir: &Path) -> Result<DirLock> {
+        let path = lock_path(dir);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(KvsError::Locked(dir.to_owned()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn lock_path(dir: &Path) -> PathBuf {
+    dir.join("LOCK")
+}
@@ -1,29 +1,353 @@
-use std::{future::Future, pin::Pin};
+use std::{
+    future::Future,
+    ops::{Bound, RangeBounds},
+    pin::Pin,
+    time::Duration,
+};
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
-use crate::Result;
+pub(crate) use self::batch::BatchOp;
+pub use self::batch::WriteBatch;
+pub use self::kvs::{
+    CacheStats, Compression, Durability, EncryptionKey, KvStore, KvStoreOptions, KvStoreSnapshot,
+};
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+pub use self::transaction::{Transaction, Version};
+use serde::{Deserialize, Serialize};
 
+use crate::{KvsError, Result};
+
+mod batch;
+mod expiry;
 mod kvs;
+mod lock;
 mod sled;
+mod transaction;
 
 /// Trait for a key value storage engine.
 /// box dyn future 需要加上Pin才能await
+///
+/// Keys and values are arbitrary bytes. The string methods are convenience wrappers
+/// around the byte methods.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// A read-only view of the engine at the time `snapshot` was called.
+    type Snapshot: KvsSnapshot;
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    #[allow(clippy::type_complexity)]
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Removes a given key and returns its value.
+    ///
+    /// Returns `None`, without writing anything, if the given key does not exist.
+    #[allow(clippy::type_complexity)]
+    fn take(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>>;
+
+    /// Removes a given key if it exists.
+    ///
+    /// Unlike `remove_bytes`, a missing key is not an error. Returns whether the key
+    /// was removed.
+    fn remove_if_exists(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<bool>> + Send>>;
+
+    /// Applies all writes of `batch` atomically.
+    ///
+    /// After a crash either all or none of the writes are visible.
+    fn write_batch(&self, batch: WriteBatch) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// `None` stands for a missing key, so `expected: None` only sets a new key and
+    /// `new: None` removes the key. If the current value differs from `expected`,
+    /// nothing is written and the current value is returned in the error.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Pin<Box<dyn Future<Output = Result<CompareAndSwapResult>> + Send>>;
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+    fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>>;
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>> {
+        let fut = self.get_bytes(key.into_bytes());
+        Box::pin(async move { Ok(fut.await?.map(String::from_utf8).transpose()?) })
+    }
 
-    /// Removes a given key.
+    /// Removes a given string key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+    fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// Integers are stored as decimal strings. A missing key counts as zero.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the value is not an `i64` and
+    /// `KvsError::IntegerOverflow` if the new value doesn't fit in one.
+    fn incr_by(
+        &self,
+        key: Vec<u8>,
+        delta: i64,
+    ) -> Pin<Box<dyn Future<Output = Result<i64>> + Send>>;
+
+    /// Appends `suffix` to the value of a key.
+    ///
+    /// A missing key counts as an empty value.
+    fn append(
+        &self,
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// An expired key reads as missing. Any later write of the key removes the expiry.
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Returns the time left until a key expires, or `None` if it doesn't expire.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn ttl(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Option<Duration>>> + Send>>;
+
+    /// Returns up to `limit` key/value pairs with keys in `range`, ordered by key.
+    #[allow(clippy::type_complexity)]
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>>;
+
+    /// Returns up to `limit` key/value pairs with keys starting with `prefix`,
+    /// ordered by key.
+    #[allow(clippy::type_complexity)]
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>> {
+        self.scan(prefix_range(prefix), limit)
+    }
+
+    /// Takes a snapshot of the data.
+    ///
+    /// Reads through the snapshot see every write completed before this call and
+    /// none started after the snapshot was taken. Keys still expire while a
+    /// snapshot is held.
+    #[allow(clippy::type_complexity)]
+    fn snapshot(&self) -> Pin<Box<dyn Future<Output = Result<Self::Snapshot>> + Send>>;
+
+    /// Starts an optimistic transaction, see `Transaction`.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Gets the value of a given key together with its version.
+    ///
+    /// This is how a `Transaction` reads.
+    #[allow(clippy::type_complexity)]
+    fn get_versioned(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(Option<Vec<u8>>, Version)>> + Send>>;
+
+    /// Applies all writes of `batch` atomically if every key in `reads` still has
+    /// the version it was read at.
+    ///
+    /// This is how a `Transaction` commits.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key changed.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Version)>,
+        batch: WriteBatch,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Returns the named keyspace `name`.
+    ///
+    /// A keyspace holds keys of its own, apart from the default keyspace of the
+    /// engine itself and from every other keyspace. The returned engine works on
+    /// the keyspace only, and its snapshots and transactions don't span others.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if the keyspace doesn't exist.
+    fn keyspace(&self, name: &str) -> Result<Self>;
+
+    /// Creates the named keyspace `name`.
+    ///
+    /// Names consist of up to 64 ASCII letters, digits, `-` and `_`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceExists` if the keyspace exists already and
+    /// `KvsError::InvalidKeyspaceName` if the name is not valid.
+    fn create_keyspace(&self, name: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Drops the named keyspace `name` together with all its keys.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyspaceNotFound` if the keyspace doesn't exist.
+    fn drop_keyspace(&self, name: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Returns the names of all named keyspaces, ordered by name.
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>>;
+}
+
+/// A consistent read-only view of a `KvsEngine` at a point in time.
+///
+/// Snapshots are cheap to clone and all clones share the same view.
+pub trait KvsSnapshot: Clone + Send + 'static {
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key did not exist when the snapshot was taken.
+    #[allow(clippy::type_complexity)]
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>>;
+
+    /// Returns up to `limit` key/value pairs with keys in `range`, ordered by key.
+    #[allow(clippy::type_complexity)]
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>>;
+
+    /// Gets the string value of a given string key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>> {
+        let fut = self.get_bytes(key.into_bytes());
+        Box::pin(async move { Ok(fut.await?.map(String::from_utf8).transpose()?) })
+    }
+
+    /// Returns up to `limit` key/value pairs with keys starting with `prefix`,
+    /// ordered by key.
+    #[allow(clippy::type_complexity)]
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>> {
+        self.scan(prefix_range(prefix), limit)
+    }
+}
+
+/// A key and its value.
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// Result of `KvsEngine::compare_and_swap`.
+pub type CompareAndSwapResult = std::result::Result<(), CompareAndSwapError>;
+
+/// The value found by a failed `KvsEngine::compare_and_swap`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompareAndSwapError {
+    /// The current value of the key, `None` if the key doesn't exist.
+    pub current: Option<Vec<u8>>,
+}
+
+/// Owned bounds of a key range, which can be moved to another thread.
+pub(crate) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Copies the bounds of `range`.
+pub(crate) fn key_range<R: RangeBounds<Vec<u8>>>(range: &R) -> KeyRange {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+/// Adds `delta` to the integer stored in `value`, where a missing value counts as zero.
+pub(crate) fn incr_value(value: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let current = match value {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or(KvsError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(delta).ok_or(KvsError::IntegerOverflow)
+}
+
+/// Longest name of a keyspace.
+const MAX_KEYSPACE_NAME_LEN: usize = 64;
+
+/// Checks that `name` is a valid keyspace name, which is also safe as a file name.
+pub(crate) fn check_keyspace_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_KEYSPACE_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(KvsError::InvalidKeyspaceName(name.to_owned()))
+    }
+}
+
+/// Returns the range of all keys starting with `prefix`.
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> KeyRange {
+    // The end is the prefix with its last byte incremented, after dropping
+    // the trailing 0xff bytes which can't be incremented.
+    let mut end = prefix.clone();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+    let end = match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix), end)
 }
@@ -1,36 +1,127 @@
-use std::{future::Future, pin::Pin};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    future::Future,
+    io,
+    ops::RangeBounds,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
 
-use super::KvsEngine;
+use super::{
+    check_keyspace_name,
+    expiry::{self, Reaper, DEFAULT_REAP_INTERVAL},
+    incr_value, key_range,
+    lock::DirLock,
+    BatchOp, CompareAndSwapError, CompareAndSwapResult, KeyRange, KvPair, KvsEngine, KvsSnapshot,
+    Version, WriteBatch,
+};
 use crate::{thread_pool::ThreadPool, KvsError, Result};
+use fs2::FileExt;
 use log::error;
-use sled::Db;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Db, IVec, Transactional, Tree,
+};
 use tokio::sync::oneshot;
 
+/// How long opening a database waits for a database dropped before to be closed.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the lock of a database which is being closed is tried.
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Name of the tree which maps expiring keys to their expiry time.
+const EXPIRIES_TREE: &str = "__kvs_expiries";
+
+/// Prefix of the names of the value trees of keyspaces.
+const KEYSPACE_PREFIX: &str = "__kvs_keyspace/";
+
+/// Prefix of the names of the expiry trees of keyspaces.
+const KEYSPACE_EXPIRIES_PREFIX: &str = "__kvs_expiries/";
+
 /// Wrapper of `sled::Db`
+///
+/// The expiry times of keys set with a TTL are kept in a separate tree. Writes
+/// update both trees in a transaction. The default keyspace is the default tree
+/// of the database, and every named keyspace has a value and an expiry tree of
+/// its own.
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
     db: Db,
+    // the trees of the keyspace
+    values: Tree,
+    expiries: Tree,
+    snapshots: Arc<Snapshots>,
+    _reaper: Arc<Reaper>,
+    _lock: Option<Arc<DirLock>>,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
     /// Creates a `SledKvsEngine` from `sled::Db`.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
         let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        let expiries = db.open_tree(EXPIRIES_TREE)?;
+        let reaper = {
+            let db = db.clone();
+            Reaper::spawn(DEFAULT_REAP_INTERVAL, move || {
+                if let Err(e) = reap_keyspaces(&db) {
+                    error!("Failed to remove expired keys: {}", e);
+                }
+            })?
+        };
+        Ok(SledKvsEngine {
+            pool,
+            values: Tree::clone(&db),
+            db,
+            expiries,
+            snapshots: Arc::new(Snapshots::default()),
+            _reaper: Arc::new(reaper),
+            _lock: None,
+        })
     }
-}
 
-impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        let db = self.db.clone();
+    /// Opens a sled database in the given directory.
+    ///
+    /// Like `KvStore::open`, it locks the directory until the engine and all its
+    /// clones are dropped.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another store has the directory open.
+    pub fn open(path: impl AsRef<Path>, concurrency: u32) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        let lock = DirLock::acquire(path)?;
+        wait_for_close(path, CLOSE_TIMEOUT)?;
+        let engine = Self::new(sled::open(path)?, concurrency)?;
+        Ok(SledKvsEngine {
+            _lock: Some(Arc::new(lock)),
+            ..engine
+        })
+    }
+
+    /// Runs `job` with the value and expiry trees on the thread pool.
+    fn spawn<T, F>(&self, job: F) -> Pin<Box<dyn Future<Output = Result<T>> + Send>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Tree, &Tree) -> Result<T> + Send + 'static,
+    {
+        let values = self.values.clone();
+        let expiries = self.expiries.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = db
-                .insert(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
+            let res = job(&values, &expiries);
+            // The database is closed with its last handle, so the handles of the job are
+            // released before the caller can drop the engine and open it again.
+            drop((values, expiries));
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -44,49 +135,684 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         Box::pin(fut)
     }
 
-    fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+    /// Returns the `Keeper` of the snapshots of the keyspace, which every write uses.
+    fn keeper(&self) -> Keeper {
+        Keeper {
+            snapshots: Arc::clone(&self.snapshots),
+            tree: self.values.name(),
+        }
+    }
+
+    /// Returns whether the named keyspace `name` exists.
+    fn has_keyspace(&self, name: &str) -> bool {
+        let tree_name = format!("{}{}", KEYSPACE_PREFIX, name);
+        self.db
+            .tree_names()
+            .iter()
+            .any(|tree| tree == tree_name.as_bytes())
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
+    type Snapshot = SledSnapshot;
+
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            transaction(values, expiries, |values, expiries| {
+                keeper.keep(values, expiries, &key)?;
+                values.insert(&key[..], &value[..])?;
+                expiries.remove(&key[..])?;
+                Ok(())
+            })?;
+            values.flush()?;
+            Ok(())
+        })
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>> {
+        self.spawn(move |values, expiries| {
+            let value = transaction(values, expiries, |values, expiries| {
+                live_value(values, expiries, &key)
+            })?;
+            Ok(value.map(|i_vec| i_vec.to_vec()))
+        })
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            transaction(values, expiries, |values, expiries| {
+                if live_value(values, expiries, &key)?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+                }
+                keeper.keep(values, expiries, &key)?;
+                values.remove(&key[..])?;
+                expiries.remove(&key[..])?;
+                Ok(())
+            })?;
+            values.flush()?;
+            Ok(())
+        })
+    }
+
+    fn take(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            let value = transaction(values, expiries, |values, expiries| {
+                let value = live_value(values, expiries, &key)?;
+                if value.is_some() {
+                    keeper.keep(values, expiries, &key)?;
+                    values.remove(&key[..])?;
+                    expiries.remove(&key[..])?;
+                }
+                Ok(value)
+            })?;
+            if value.is_some() {
+                values.flush()?;
             }
-        });
-        let fut = async move {
-            match rx.await {
-                Ok(ret) => ret,
-                Err(_) => Err(KvsError::StringError("tokio recv error".to_owned())),
+            Ok(value.map(|i_vec| i_vec.to_vec()))
+        })
+    }
+
+    fn remove_if_exists(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> {
+        let fut = self.take(key);
+        Box::pin(async move { Ok(fut.await?.is_some()) })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            let keys = batch_keys(&batch);
+            let (values_batch, expiries_batch) = sled_batches(batch);
+            transaction(values, expiries, |values, expiries| {
+                for key in &keys {
+                    keeper.keep(values, expiries, key)?;
+                }
+                values.apply_batch(&values_batch)?;
+                expiries.apply_batch(&expiries_batch)?;
+                Ok(())
+            })?;
+            values.flush()?;
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Pin<Box<dyn Future<Output = Result<CompareAndSwapResult>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            let swapped = transaction(values, expiries, |values, expiries| {
+                let current = live_value(values, expiries, &key)?;
+                if current.as_deref() != expected.as_deref() {
+                    return Ok(Err(CompareAndSwapError {
+                        current: current.map(|value| value.to_vec()),
+                    }));
+                }
+                keeper.keep(values, expiries, &key)?;
+                match &new {
+                    Some(value) => values.insert(&key[..], &value[..])?,
+                    None => values.remove(&key[..])?,
+                };
+                expiries.remove(&key[..])?;
+                Ok(Ok(()))
+            })?;
+            if swapped.is_ok() {
+                values.flush()?;
             }
-        };
-        Box::pin(fut)
+            Ok(swapped)
+        })
     }
 
-    fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (|| {
-                db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-                db.flush()?;
+    fn incr_by(
+        &self,
+        key: Vec<u8>,
+        delta: i64,
+    ) -> Pin<Box<dyn Future<Output = Result<i64>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            let n = transaction(values, expiries, |values, expiries| {
+                let current = live_value(values, expiries, &key)?;
+                let n = incr_value(current.as_deref(), delta)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                keeper.keep(values, expiries, &key)?;
+                values.insert(&key[..], n.to_string().into_bytes())?;
+                expiries.remove(&key[..])?;
+                Ok(n)
+            })?;
+            values.flush()?;
+            Ok(n)
+        })
+    }
+
+    fn append(
+        &self,
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            transaction(values, expiries, |values, expiries| {
+                let mut value = live_value(values, expiries, &key)?
+                    .map(|value| value.to_vec())
+                    .unwrap_or_default();
+                value.extend_from_slice(&suffix);
+                keeper.keep(values, expiries, &key)?;
+                values.insert(&key[..], value)?;
+                expiries.remove(&key[..])?;
                 Ok(())
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+            })?;
+            values.flush()?;
+            Ok(())
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let expires_at = expiry::expires_at(ttl);
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            transaction(values, expiries, |values, expiries| {
+                keeper.keep(values, expiries, &key)?;
+                values.insert(&key[..], &value[..])?;
+                expiries.insert(&key[..], &expires_at.to_be_bytes()[..])?;
+                Ok(())
+            })?;
+            values.flush()?;
+            Ok(())
+        })
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Option<Duration>>> + Send>> {
+        self.spawn(move |values, expiries| {
+            transaction(values, expiries, |values, expiries| {
+                let time_left = match expiries.get(&key)? {
+                    Some(expires_at) => expiry::time_left(decode_expiry(&expires_at)).map(Some),
+                    None => values.get(&key)?.map(|_| None),
+                };
+                time_left.ok_or(ConflictableTransactionError::Abort(KvsError::KeyNotFound))
+            })
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>> {
+        let range = key_range(&range);
+        self.spawn(move |values, expiries| {
+            let now = expiry::now_millis();
+            let mut pairs = Vec::new();
+            for pair in values.range(range) {
+                if pairs.len() == limit {
+                    break;
+                }
+                let (key, value) = pair?;
+                let expired = expiries
+                    .get(&key)?
+                    .is_some_and(|expires_at| decode_expiry(&expires_at) <= now);
+                if !expired {
+                    pairs.push((key.to_vec(), value.to_vec()));
+                }
+            }
+            Ok(pairs)
+        })
+    }
+
+    /// Gets the value of a given key together with its version.
+    ///
+    /// sled keeps no versions, so the version is the value itself.
+    fn get_versioned(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<(Option<Vec<u8>>, Version)>> + Send>> {
+        let fut = self.get_bytes(key);
+        Box::pin(async move {
+            let value = fut.await?;
+            let version = Version::value(value.clone());
+            Ok((value, version))
+        })
+    }
+
+    /// Applies all writes of `batch` atomically if every key in `reads` still has
+    /// the value it was read with.
+    ///
+    /// The values are compared and the writes applied in a single sled transaction.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Version)>,
+        batch: WriteBatch,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keeper = self.keeper();
+        self.spawn(move |values, expiries| {
+            let keys = batch_keys(&batch);
+            let (values_batch, expiries_batch) = sled_batches(batch);
+            transaction(values, expiries, |values, expiries| {
+                for (key, version) in &reads {
+                    let value = live_value(values, expiries, key)?.map(|value| value.to_vec());
+                    if Version::value(value) != *version {
+                        return Err(ConflictableTransactionError::Abort(
+                            KvsError::TransactionConflict,
+                        ));
+                    }
+                }
+                for key in &keys {
+                    keeper.keep(values, expiries, key)?;
+                }
+                values.apply_batch(&values_batch)?;
+                expiries.apply_batch(&expiries_batch)?;
+                Ok(())
+            })?;
+            values.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Takes a snapshot of the data.
+    ///
+    /// sled has no snapshots of its own, so the snapshot is built up by the writes
+    /// after it, see `Snapshots`. Taking it copies nothing.
+    fn snapshot(&self) -> Pin<Box<dyn Future<Output = Result<SledSnapshot>> + Send>> {
+        let snapshots = Arc::clone(&self.snapshots);
+        self.spawn(move |values, expiries| {
+            let view = Arc::new(SnapshotView {
+                tree: values.name(),
+                kept: Mutex::new(BTreeMap::new()),
+            });
+            // no write transaction runs alongside this one
+            transaction(values, expiries, |_, _| {
+                snapshots.register(&view);
+                Ok(())
+            })?;
+            Ok(SledSnapshot {
+                values: values.clone(),
+                expiries: expiries.clone(),
+                view,
+            })
+        })
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        if !self.has_keyspace(name) {
+            return Err(KvsError::KeyspaceNotFound(name.to_owned()));
+        }
+        let (values, expiries) = keyspace_trees(&self.db, name)?;
+        Ok(SledKvsEngine {
+            values,
+            expiries,
+            ..self.clone()
+        })
+    }
+
+    /// Creates the named keyspace `name`.
+    ///
+    /// Its trees are created empty.
+    fn create_keyspace(&self, name: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let engine = self.clone();
+        let name = name.to_owned();
+        self.spawn(move |_, _| {
+            check_keyspace_name(&name)?;
+            if engine.has_keyspace(&name) {
+                return Err(KvsError::KeyspaceExists(name));
             }
+            let (values, expiries) = keyspace_trees(&engine.db, &name)?;
+            // the expiries of a keyspace dropped before may be left over after a crash
+            expiries.clear()?;
+            values.flush()?;
+            Ok(())
+        })
+    }
+
+    /// Drops the named keyspace `name` together with all its keys.
+    ///
+    /// Handles of the keyspace which are still held elsewhere must not be used
+    /// anymore.
+    fn drop_keyspace(&self, name: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let db = self.db.clone();
+        let name = name.to_owned();
+        self.spawn(move |_, _| {
+            // the expiry tree goes last, so that no value outlives its expiry
+            if !db.drop_tree(format!("{}{}", KEYSPACE_PREFIX, name))? {
+                return Err(KvsError::KeyspaceNotFound(name));
+            }
+            db.drop_tree(format!("{}{}", KEYSPACE_EXPIRIES_PREFIX, name))?;
+            db.flush()?;
+            Ok(())
+        })
+    }
+
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>> {
+        let names = keyspace_names(&self.db);
+        Box::pin(async move { Ok(names) })
+    }
+}
+
+/// A value together with its expiry time.
+type Entry = (Vec<u8>, Option<u64>);
+
+/// The snapshots of a database which are still held.
+///
+/// A snapshot starts out empty. Every write hands the values its keys have before it
+/// to the snapshots of its tree which have none for them yet, and reads through a
+/// snapshot prefer these over the trees, where the keys nothing wrote since are left
+/// as they were. Writes and taking a snapshot run in sled transactions, which never
+/// run alongside each other, so a snapshot sees exactly the writes committed before.
+#[derive(Default)]
+struct Snapshots {
+    views: Mutex<Vec<Weak<SnapshotView>>>,
+}
+
+impl Snapshots {
+    /// Adds `view` to the snapshots which writes hand their old values to.
+    fn register(&self, view: &Arc<SnapshotView>) {
+        let mut views = self.views.lock().unwrap();
+        // a transaction may run more than once
+        if !views.iter().any(|held| held.as_ptr() == Arc::as_ptr(view)) {
+            views.push(Arc::downgrade(view));
+        }
+    }
+
+    /// Returns the snapshots of the value tree `tree` which are still held.
+    fn of_tree(&self, tree: &[u8]) -> Vec<Arc<SnapshotView>> {
+        let mut views = self.views.lock().unwrap();
+        views.retain(|view| view.strong_count() > 0);
+        views
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|view| view.tree == tree)
+            .collect()
+    }
+}
+
+/// A snapshot of a value tree, shared by all clones of its `SledSnapshot`.
+struct SnapshotView {
+    tree: IVec,
+    // the keys written since the snapshot was taken, with their entry at that time
+    // or `None` if they didn't exist
+    kept: Mutex<BTreeMap<Vec<u8>, Option<Entry>>>,
+}
+
+/// Hands the values of keys about to be written to the snapshots of a tree.
+struct Keeper {
+    snapshots: Arc<Snapshots>,
+    tree: IVec,
+}
+
+impl Keeper {
+    /// Keeps the current entry of `key` for the snapshots which have none yet.
+    ///
+    /// It must run in the transaction writing `key`, before the write.
+    fn keep(
+        &self,
+        values: &TransactionalTree,
+        expiries: &TransactionalTree,
+        key: &[u8],
+    ) -> ConflictableTransactionResult<(), KvsError> {
+        let views = self.snapshots.of_tree(&self.tree);
+        if views.is_empty() {
+            return Ok(());
+        }
+        let expires_at = expiries
+            .get(key)?
+            .map(|expires_at| decode_expiry(&expires_at));
+        let entry = values.get(key)?.map(|value| (value.to_vec(), expires_at));
+        for view in views {
+            let mut kept = view.kept.lock().unwrap();
+            kept.entry(key.to_vec()).or_insert_with(|| entry.clone());
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of a `SledKvsEngine`, see `KvsEngine::snapshot` and `Snapshots`.
+#[derive(Clone)]
+pub struct SledSnapshot {
+    values: Tree,
+    expiries: Tree,
+    view: Arc<SnapshotView>,
+}
+
+impl SledSnapshot {
+    /// Reads the entry of `key` as of the snapshot, also if it expired since.
+    fn entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        // A write changing the key after it is read from the trees has handed its
+        // entry to the view before, so the view is looked at last.
+        let value = self.values.get(key)?;
+        let expires_at = self
+            .expiries
+            .get(key)?
+            .map(|expires_at| decode_expiry(&expires_at));
+        if let Some(kept) = self.view.kept.lock().unwrap().get(key) {
+            return Ok(kept.clone());
+        }
+        Ok(value.map(|value| (value.to_vec(), expires_at)))
+    }
+
+    /// Returns up to `limit` pairs in `range` as of the snapshot.
+    fn pairs(&self, range: KeyRange, limit: usize) -> Result<Vec<KvPair>> {
+        let now = expiry::now_millis();
+        let live = |expires_at: Option<u64>| expires_at.is_none_or(|expires_at| expires_at > now);
+        let mut pairs = BTreeMap::new();
+        for key in self.values.range(range.clone()).keys() {
+            if pairs.len() == limit {
+                break;
+            }
+            let key = key?;
+            if let Some((value, expires_at)) = self.entry(&key)? {
+                if live(expires_at) {
+                    pairs.insert(key.to_vec(), value);
+                }
+            }
+        }
+        // the keys removed from the trees since, looked at after the trees as above
+        let kept = self.view.kept.lock().unwrap();
+        for (key, entry) in kept.range(range) {
+            if let Some((value, expires_at)) = entry {
+                if live(*expires_at) {
+                    pairs.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+        Ok(pairs.into_iter().take(limit).collect())
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Vec<u8>>>> + Send>> {
+        let now = expiry::now_millis();
+        let value = self.entry(&key).map(|entry| {
+            entry
+                .filter(|(_, expires_at)| expires_at.is_none_or(|expires_at| expires_at > now))
+                .map(|(value, _)| value)
         });
-        let fut = async move {
-            match rx.await {
-                Ok(ret) => ret,
-                Err(_) => Err(KvsError::StringError("tokio recv error".to_owned())),
+        Box::pin(async move { value })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<KvPair>>> + Send>> {
+        let pairs = self.pairs(key_range(&range), limit);
+        Box::pin(async move { pairs })
+    }
+}
+
+/// Returns the keys written by `batch`.
+fn batch_keys(batch: &WriteBatch) -> Vec<Vec<u8>> {
+    batch
+        .ops()
+        .iter()
+        .map(|op| match op {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key.clone(),
+        })
+        .collect()
+}
+
+/// Splits `batch` into the batches of the value and expiry trees.
+fn sled_batches(batch: WriteBatch) -> (sled::Batch, sled::Batch) {
+    let mut values_batch = sled::Batch::default();
+    let mut expiries_batch = sled::Batch::default();
+    for op in batch.into_ops() {
+        match op {
+            BatchOp::Set { key, value } => {
+                expiries_batch.remove(&key[..]);
+                values_batch.insert(key, value);
             }
-        };
-        Box::pin(fut)
+            BatchOp::Remove { key } => {
+                expiries_batch.remove(&key[..]);
+                values_batch.remove(key);
+            }
+        }
+    }
+    (values_batch, expiries_batch)
+}
+
+/// Runs `f` in a transaction over the value and expiry trees.
+fn transaction<T, F>(values: &Tree, expiries: &Tree, f: F) -> Result<T>
+where
+    F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>,
+{
+    (values, expiries)
+        .transaction(|(values, expiries)| f(values, expiries))
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+/// Reads the value of `key`, or `None` if it doesn't exist or expired.
+fn live_value(
+    values: &TransactionalTree,
+    expiries: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<IVec>, KvsError> {
+    if let Some(expires_at) = expiries.get(key)? {
+        if decode_expiry(&expires_at) <= expiry::now_millis() {
+            return Ok(None);
+        }
+    }
+    Ok(values.get(key)?)
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    bytes.try_into().map_or(0, u64::from_be_bytes)
+}
+
+/// Waits until a database dropped before in the directory at `path` is closed.
+///
+/// Sled finishes the pending writes of a dropped database on its IO threads, which
+/// keep its file locked for a moment, and `sled::open` fails instead of waiting.
+/// The directory is locked, so no other engine can open the database meanwhile.
+///
+/// # Errors
+///
+/// It returns `KvsError::Locked` if the database is still locked after `timeout`,
+/// e.g. by a process which doesn't respect the directory lock.
+fn wait_for_close(path: &Path, timeout: Duration) -> Result<()> {
+    let file = match File::open(path.join("db")) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let start = Instant::now();
+    loop {
+        match file.try_lock_exclusive() {
+            // unlocked when the file is closed
+            Ok(()) => return Ok(()),
+            Err(e) if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() => {
+                return Err(e.into())
+            }
+            Err(_) if start.elapsed() >= timeout => return Err(KvsError::Locked(path.to_owned())),
+            Err(_) => thread::sleep(CLOSE_POLL_INTERVAL),
+        }
+    }
+}
+
+/// Opens the value and expiry trees of the named keyspace `name`.
+fn keyspace_trees(db: &Db, name: &str) -> Result<(Tree, Tree)> {
+    let values = db.open_tree(format!("{}{}", KEYSPACE_PREFIX, name))?;
+    let expiries = db.open_tree(format!("{}{}", KEYSPACE_EXPIRIES_PREFIX, name))?;
+    Ok((values, expiries))
+}
+
+/// Returns the names of the named keyspaces, ordered by name.
+fn keyspace_names(db: &Db) -> Vec<String> {
+    let mut names: Vec<_> = db
+        .tree_names()
+        .iter()
+        .filter_map(|tree| tree.strip_prefix(KEYSPACE_PREFIX.as_bytes()))
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Removes the keys which expired in every keyspace.
+fn reap_keyspaces(db: &Db) -> Result<()> {
+    reap(db, &db.open_tree(EXPIRIES_TREE)?)?;
+    for name in keyspace_names(db) {
+        let (values, expiries) = keyspace_trees(db, &name)?;
+        reap(&values, &expiries)?;
+    }
+    Ok(())
+}
+
+/// Removes the keys which expired.
+fn reap(values: &Tree, expiries: &Tree) -> Result<()> {
+    let now = expiry::now_millis();
+    for entry in expiries.iter() {
+        let (key, expires_at) = entry?;
+        if decode_expiry(&expires_at) > now {
+            continue;
+        }
+        transaction(values, expiries, |values, expiries| {
+            // the key may have been written again meanwhile
+            if expiries.get(&key)?.as_ref() == Some(&expires_at) {
+                values.remove(&key)?;
+                expiries.remove(&key)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn wait_for_close_times_out() -> Result<()> {
+        let dir = TempDir::new()?;
+        wait_for_close(dir.path(), Duration::ZERO)?;
+
+        let file = File::create(dir.path().join("db"))?;
+        file.lock_exclusive()?;
+        let res = wait_for_close(dir.path(), Duration::from_millis(50));
+        assert!(matches!(res, Err(KvsError::Locked(_))));
+        file.unlock()?;
+        wait_for_close(dir.path(), Duration::from_millis(50))
     }
 }
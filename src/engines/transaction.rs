@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use super::{KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+
+/// An optimistic transaction over several keys, started by `KvsEngine::begin`.
+///
+/// Reads go to the engine and record the version of each key read. Writes are
+/// buffered until `commit`, which applies them atomically only if none of the
+/// keys read has changed in the meantime. Reads see the transaction's own writes.
+/// Dropping a transaction without committing it discards its writes.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # #[tokio::main]
+/// # async fn main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// # let store: KvStore<RayonThreadPool> = KvStore::open(temp_dir.path(), 2)?;
+/// store.set("alice".to_owned(), "10".to_owned()).await?;
+/// let mut tx = store.begin();
+/// let alice: i64 = tx.get("alice".to_owned()).await?.unwrap().parse().unwrap();
+/// tx.set("alice".to_owned(), (alice - 3).to_string());
+/// tx.set("bob".to_owned(), "3".to_owned());
+/// tx.commit().await?;
+/// assert_eq!(store.get("bob".to_owned()).await?, Some("3".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    // the version of each key when it was first read
+    reads: BTreeMap<Vec<u8>, Version>,
+    // `None` for a removal
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// The version of a key read by a `Transaction`.
+///
+/// A key changed if its current version differs from the one read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version(VersionKind);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum VersionKind {
+    // sequence number of the write, `None` for a missing key
+    Seq(Option<u64>),
+    // for engines without versions, the value itself
+    Value(Option<Vec<u8>>),
+}
+
+impl Version {
+    pub(crate) fn seq(seq: Option<u64>) -> Version {
+        Version(VersionKind::Seq(seq))
+    }
+
+    pub(crate) fn value(value: Option<Vec<u8>>) -> Version {
+        Version(VersionKind::Value(value))
+    }
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Transaction<E> {
+        Transaction {
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.engine.get_versioned(key.clone()).await?;
+        // a key read again is validated against its first read
+        self.reads.entry(key).or_insert(version);
+        Ok(value)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.into_bytes()).await?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Sets the value of a key on commit.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Sets the value of a string key to a string on commit.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a given key on commit.
+    ///
+    /// The key is read to check that it exists, so the commit fails if it is
+    /// removed by someone else first.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone()).await?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Removes a given string key on commit.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    /// Applies the writes atomically if none of the keys read has changed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key read was written by
+    /// someone else since. Nothing is written then, and the transaction can be
+    /// retried from the start.
+    pub async fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+        let reads = self.reads.into_iter().collect();
+        self.engine.commit_transaction(reads, batch).await
+    }
+
+    /// Discards the transaction without writing anything.
+    pub fn abort(self) {}
+}
@@ -1,5 +1,5 @@
 use failure::Fail;
-use std::{io, string::FromUtf8Error};
+use std::{io, path::PathBuf, string::FromUtf8Error};
 
 /// Error type for kvs.
 #[derive(Fail, Debug)]
@@ -23,6 +23,48 @@ pub enum KvsError {
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// A log record failed validation.
+    /// It indicates a corrupted or partially written log file.
+    #[fail(
+        display = "corrupted log record in generation {} at offset {}: {}",
+        gen, offset, reason
+    )]
+    CorruptedLog {
+        /// Generation of the log file.
+        gen: u64,
+        /// Offset of the record in the log file.
+        offset: u64,
+        /// Why the record is invalid.
+        reason: String,
+    },
+    /// The data directory is locked by another open store.
+    #[fail(display = "{:?} is already in use by another process", _0)]
+    Locked(PathBuf),
+    /// The value of a key to increment is not an integer.
+    #[fail(display = "Value is not an integer")]
+    NotAnInteger,
+    /// Incrementing a value overflows an `i64`.
+    #[fail(display = "Integer overflow")]
+    IntegerOverflow,
+    /// A key read by a transaction was written by someone else before it committed.
+    #[fail(display = "Transaction conflict")]
+    TransactionConflict,
+    /// The keyspace doesn't exist.
+    #[fail(display = "Keyspace {} not found", _0)]
+    KeyspaceNotFound(String),
+    /// Creating a keyspace that already exists.
+    #[fail(display = "Keyspace {} already exists", _0)]
+    KeyspaceExists(String),
+    /// A keyspace name which is empty, too long or has characters other than
+    /// ASCII letters, digits, `-` and `_`.
+    #[fail(display = "Invalid keyspace name: {:?}", _0)]
+    InvalidKeyspaceName(String),
+    /// A key file which holds neither 32 bytes nor 64 hexadecimal digits.
+    #[fail(display = "{:?} does not hold a 256-bit key", _0)]
+    InvalidEncryptionKey(PathBuf),
+    /// Writing to a store that is opened read-only.
+    #[fail(display = "The store is opened read-only")]
+    ReadOnly,
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -2,7 +2,11 @@
 //! A simple key/value store.
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    CacheStats, CompareAndSwapError, CompareAndSwapResult, Compression, Durability, EncryptionKey,
+    KvPair, KvStore, KvStoreOptions, KvStoreSnapshot, KvsEngine, KvsSnapshot, SledKvsEngine,
+    SledSnapshot, Transaction, Version, WriteBatch,
+};
 pub use error::{KvsError, Result};
 pub use data_struct::{SafeDeque, UnsafeList, LibVec, Arc};
 
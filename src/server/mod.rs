@@ -1,4 +1,4 @@
-use crate::{KvsEngine, Result, connection::Connection, common::{Request, Response}};
+use crate::{KvsEngine, KvsError, Result, Transaction, connection::Connection, common::{Request, Response}};
 use std::{net::SocketAddr, sync::Arc};
 use log::{error, info};
 use tokio::{net::{TcpListener, TcpStream}, sync::{Semaphore, broadcast, mpsc}, signal};
@@ -74,6 +74,7 @@ fn serve<E: KvsEngine>(engine: E, socket: TcpStream, _peer_addr: SocketAddr) {
     let mut handler = Handler {
         engine,
         connection: Connection::new(socket),
+        transaction: None,
     };
     tokio::spawn(async move {
         if let Err(e) = handler.run().await {
@@ -86,24 +87,20 @@ fn serve<E: KvsEngine>(engine: E, socket: TcpStream, _peer_addr: SocketAddr) {
 struct Handler<E: KvsEngine> {
     engine: E,
     connection: Connection,
+    // the transaction begun on this connection, dropped with it if not committed,
+    // and the keyspace it was begun in
+    transaction: Option<(Option<String>, Transaction<E>)>,
 }
 
 impl<E: KvsEngine> Handler<E> {
     async fn run(&mut self) -> Result<()> {
         loop {
             let resp = match self.connection.read_req().await? {
-                Request::Get{ key } => {
-                    let get_future = self.engine.get(key);
-                    get_future.await.map(Response::Get)
-                }
-                Request::Set{ key, value } => {
-                    let set_future = self.engine.set(key, value);
-                    set_future.await.map(|_| Response::Set)
-                }
-                Request::Remove { key } => {
-                    let rm_future = self.engine.remove(key);
-                    rm_future.await.map(|_| Response::Remove)
-                }
+                Request::InKeyspace { keyspace, request } => match self.engine.keyspace(&keyspace) {
+                    Ok(engine) => self.handle(engine, Some(keyspace), *request).await,
+                    Err(e) => Err(e),
+                },
+                req => self.handle(self.engine.clone(), None, req).await,
             };
             match resp {
                 Ok(resp) => self.connection.write_json(serde_json::to_string(&resp).unwrap().as_str()).await?,
@@ -114,4 +111,134 @@ impl<E: KvsEngine> Handler<E> {
             }
         }
     }
-}
\ No newline at end of file
+
+    /// Handles a request to `engine`, which is the keyspace `keyspace` or the default one.
+    async fn handle(&mut self, engine: E, keyspace: Option<String>, req: Request) -> Result<Response> {
+        match req {
+            Request::Get{ key } => match self.transaction_in(&keyspace)? {
+                Some(transaction) => transaction.get_bytes(key).await.map(Response::Get),
+                None => {
+                    let get_future = engine.get_bytes(key);
+                    get_future.await.map(Response::Get)
+                }
+            },
+            Request::Set{ key, value } => match self.transaction_in(&keyspace)? {
+                Some(transaction) => {
+                    transaction.set_bytes(key, value);
+                    Ok(Response::Set)
+                }
+                None => {
+                    let set_future = engine.set_bytes(key, value);
+                    set_future.await.map(|_| Response::Set)
+                }
+            },
+            Request::Remove { key } => match self.transaction_in(&keyspace)? {
+                Some(transaction) => transaction.remove_bytes(key).await.map(|_| Response::Remove),
+                None => {
+                    let rm_future = engine.remove_bytes(key);
+                    rm_future.await.map(|_| Response::Remove)
+                }
+            },
+            Request::Take { key } => {
+                self.outside_transaction()?;
+                let take_future = engine.take(key);
+                take_future.await.map(Response::Take)
+            }
+            Request::RemoveIfExists { key } => {
+                self.outside_transaction()?;
+                let rm_future = engine.remove_if_exists(key);
+                rm_future.await.map(Response::RemoveIfExists)
+            }
+            Request::Scan { start, end, limit } => {
+                let scan_future = engine.scan((start, end), limit);
+                scan_future.await.map(Response::Scan)
+            }
+            Request::Batch(batch) => {
+                self.outside_transaction()?;
+                let batch_future = engine.write_batch(batch);
+                batch_future.await.map(|_| Response::Batch)
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                self.outside_transaction()?;
+                let cas_future = engine.compare_and_swap(key, expected, new);
+                cas_future.await.map(Response::CompareAndSwap)
+            }
+            Request::IncrBy { key, delta } => {
+                self.outside_transaction()?;
+                let incr_future = engine.incr_by(key, delta);
+                incr_future.await.map(Response::IncrBy)
+            }
+            Request::Append { key, suffix } => {
+                self.outside_transaction()?;
+                let append_future = engine.append(key, suffix);
+                append_future.await.map(|_| Response::Append)
+            }
+            Request::SetWithTtl { key, value, ttl } => {
+                self.outside_transaction()?;
+                let set_future = engine.set_with_ttl(key, value, ttl);
+                set_future.await.map(|_| Response::SetWithTtl)
+            }
+            Request::Ttl { key } => {
+                let ttl_future = engine.ttl(key);
+                ttl_future.await.map(Response::Ttl)
+            }
+            Request::Begin => {
+                if self.transaction.is_some() {
+                    Err(KvsError::StringError("A transaction is already in progress".to_owned()))
+                } else {
+                    self.transaction = Some((keyspace, engine.begin()));
+                    Ok(Response::Begin)
+                }
+            }
+            Request::Commit => match self.transaction.take() {
+                Some((_, transaction)) => transaction.commit().await.map(|_| Response::Commit),
+                None => Err(no_transaction()),
+            },
+            Request::Abort => match self.transaction.take() {
+                Some((_, transaction)) => {
+                    transaction.abort();
+                    Ok(Response::Abort)
+                }
+                None => Err(no_transaction()),
+            },
+            Request::InKeyspace { .. } => {
+                Err(KvsError::StringError("Keyspace requests can't be nested".to_owned()))
+            }
+            Request::CreateKeyspace { name } => {
+                self.outside_transaction()?;
+                let create_future = engine.create_keyspace(&name);
+                create_future.await.map(|_| Response::CreateKeyspace)
+            }
+            Request::DropKeyspace { name } => {
+                self.outside_transaction()?;
+                let drop_future = engine.drop_keyspace(&name);
+                drop_future.await.map(|_| Response::DropKeyspace)
+            }
+            Request::ListKeyspaces => {
+                let list_future = engine.list_keyspaces();
+                list_future.await.map(Response::ListKeyspaces)
+            }
+        }
+    }
+
+    /// Fails if a transaction is in progress, which would not include the request.
+    fn outside_transaction(&self) -> Result<()> {
+        match self.transaction {
+            Some(_) => Err(KvsError::StringError("The request can't be part of a transaction".to_owned())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the transaction in progress, which must have been begun in `keyspace`.
+    fn transaction_in(&mut self, keyspace: &Option<String>) -> Result<Option<&mut Transaction<E>>> {
+        match &mut self.transaction {
+            Some((begun_in, transaction)) if begun_in == keyspace => Ok(Some(transaction)),
+            Some(_) => Err(KvsError::StringError("The transaction is in another keyspace".to_owned())),
+            None => Ok(None),
+        }
+    }
+}
+
+fn no_transaction() -> KvsError {
+    KvsError::StringError("No transaction in progress".to_owned())
+}
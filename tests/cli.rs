@@ -171,6 +171,49 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `kvs-server` refuses to start with a key file that holds no valid key.
+#[test]
+fn cli_invalid_encryption_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let key_path = temp_dir.path().join("key");
+    fs::write(&key_path, "not a key").unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .arg("--encryption-key-file")
+        .arg(&key_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("does not hold a 256-bit key"));
+}
+
+// A read-only `kvs-server` doesn't write to the data directory.
+#[test]
+fn cli_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    fs::remove_file(temp_dir.path().join("engine")).unwrap();
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4008", "--read-only"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none(), "server exited");
+    child.kill().expect("server exited before killed");
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -275,6 +318,14 @@ fn cli_access_server(engine: &str, addr: &str) {
         .failure()
         .stderr(contains("Key not found"));
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--if-exists", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["set", "key2", "value3", "--addr", addr])
@@ -322,6 +373,166 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2 value3\nkey3 value4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key0", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2 value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key3 value4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2 value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "-7", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value is not an integer"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["append", "key3", "!", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4!\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "session", "token", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["keyspace", "create", "space1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value5", "--keyspace", "space1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--keyspace", "space1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["keyspace", "list", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("space1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["keyspace", "drop", "space1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--keyspace", "space1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Keyspace space1 not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -0,0 +1,1640 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool};
+use kvs::{
+    CompareAndSwapError, Compression, Durability, EncryptionKey, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, KvsSnapshot, Result, SledKvsEngine, WriteBatch,
+};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+type Store = KvStore<SharedQueueThreadPool>;
+
+// Defines a module of tests per feature, with one test for each engine. A test runs
+// the check against the engine opened in a new directory, and then the optional
+// reopen check against the engine opened again from that directory.
+macro_rules! engine_tests {
+    (@engine $test:ident, $engine:ty, [$(#[$attr:meta])*], $check:ident, $concurrency:expr
+        $(, $reopened:ident)?) => {
+        $(#[$attr])*
+        async fn $test() -> Result<()> {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            $check(<$engine>::open(temp_dir.path(), $concurrency)?).await?;
+            $($reopened(<$engine>::open(temp_dir.path(), 2)?).await?;)?
+            Ok(())
+        }
+    };
+    ($($(#[$attr:meta])* $name:ident: $check:ident($concurrency:expr) $(then $reopened:ident)?;)*) => {
+        $(
+            mod $name {
+                use super::*;
+
+                engine_tests!(@engine kvs, Store, [$(#[$attr])*], $check, $concurrency
+                    $(, $reopened)?);
+                engine_tests!(@engine sled, SledKvsEngine<SharedQueueThreadPool>,
+                    [$(#[$attr])*], $check, $concurrency $(, $reopened)?);
+            }
+        )*
+    };
+}
+
+// Should get previously stored value, also after reopening the store.
+#[tokio::test]
+async fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    store.remove("key2".to_owned()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+
+    drop(store);
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+    Ok(())
+}
+
+// A damaged record must be reported with the generation and offset it lives at,
+// unless it is the last one of the newest log.
+#[tokio::test]
+async fn corrupted_record_location() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    store.set("key3".to_owned(), "value3".to_owned()).await?;
+    drop(store);
+
+    // The second record starts right after the first one: a 20 bytes header,
+    // the 4 bytes key and the 6 bytes value.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.seek(SeekFrom::Start(30 + 25))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    match Store::open(temp_dir.path(), 2) {
+        Err(KvsError::CorruptedLog { gen, offset, .. }) => assert_eq!((gen, offset), (1, 30)),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corrupted log was opened"),
+    }
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), 90);
+    Ok(())
+}
+
+// A crash can leave the last record of the newest log complete in length but
+// damaged or zeroed, which is dropped on open like an incomplete one.
+#[tokio::test]
+async fn truncate_damaged_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    drop(store);
+
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(30 + 25))?;
+    file.write_all(b"X")?;
+    drop(file);
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(fs::metadata(&log)?.len(), 30);
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+    store.set("key3".to_owned(), "value3".to_owned()).await?;
+    drop(store);
+
+    // writes continue in a new log after opening
+    let log = temp_dir.path().join("2.log");
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(&[0; 30])?;
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(fs::metadata(&log)?.len(), 30);
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key3".to_owned()).await?,
+        Some("value3".to_owned())
+    );
+    Ok(())
+}
+
+// An incomplete record at the end of the newest log is dropped on open.
+#[tokio::test]
+async fn truncate_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    OpenOptions::new().write(true).open(&log)?.set_len(45)?;
+
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(fs::metadata(&log)?.len(), 30);
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+    Ok(())
+}
+
+// A damaged length field in the middle of a log must not be mistaken for a torn write.
+#[tokio::test]
+async fn refuse_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    store.set("key3".to_owned(), "value3".to_owned()).await?;
+    drop(store);
+
+    // Make the value of the second record extend past the end of the file.
+    let log = temp_dir.path().join("1.log");
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(30 + 16))?;
+    file.write_all(&u32::MAX.to_le_bytes())?;
+    drop(file);
+
+    match Store::open(temp_dir.path(), 2) {
+        Err(KvsError::CorruptedLog { gen, offset, .. }) => assert_eq!((gen, offset), (1, 30)),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corrupted log was opened"),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), 90);
+    Ok(())
+}
+
+#[test]
+fn parse_durability() {
+    assert_eq!("never".parse(), Ok(Durability::Never));
+    assert_eq!("always".parse(), Ok(Durability::EveryWrite));
+    assert_eq!(
+        "100ms".parse(),
+        Ok(Durability::Interval(Duration::from_millis(100)))
+    );
+    assert_eq!("4096b".parse(), Ok(Durability::Bytes(4096)));
+    assert!("sometimes".parse::<Durability>().is_err());
+    assert!("12kb".parse::<Durability>().is_err());
+    assert!("0ms".parse::<Durability>().is_err());
+}
+
+// Every durability policy must keep the written data readable after reopening.
+#[tokio::test]
+async fn reopen_with_durability() -> Result<()> {
+    for durability in [
+        Durability::EveryWrite,
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::Bytes(64),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().concurrency(2).durability(durability);
+        let store = Store::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        drop(store);
+
+        let store = Store::open_with(temp_dir.path(), options)?;
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Concurrent writes are committed in groups but each one gets its own result.
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .concurrency(4)
+        .durability(Durability::EveryWrite);
+    let store = Store::open_with(temp_dir.path(), options.clone())?;
+
+    let sets: Vec<_> = (0..200)
+        .map(|i| tokio::spawn(store.set(format!("key{}", i), format!("value{}", i))))
+        .collect();
+    for set in sets {
+        set.await.unwrap()?;
+    }
+    let removes: Vec<_> = (0..200)
+        .map(|i| tokio::spawn(store.remove(format!("key{}", i % 100))))
+        .collect();
+    let mut not_found = 0;
+    for remove in removes {
+        match remove.await.unwrap() {
+            Ok(()) => {}
+            Err(KvsError::KeyNotFound) => not_found += 1,
+            Err(e) => return Err(e),
+        }
+    }
+    assert_eq!(not_found, 100);
+    drop(store);
+
+    let store = Store::open_with(temp_dir.path(), options)?;
+    for i in 0..200 {
+        let expected = if i < 100 {
+            None
+        } else {
+            Some(format!("value{}", i))
+        };
+        assert_eq!(store.get(format!("key{}", i)).await?, expected);
+    }
+    Ok(())
+}
+
+/// Checks the values of `key0` to `key9` set by iterations `first..first + 10`.
+async fn assert_latest(store: &Store, value: &str, first: usize) -> Result<()> {
+    for key_id in 0..10 {
+        let expected = format!("{}{}", value, first + key_id);
+        assert_eq!(store.get(format!("key{}", key_id)).await?, Some(expected));
+    }
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+// Overwritten values are compacted away in the background while writes go on.
+#[tokio::test(flavor = "multi_thread")]
+async fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 4)?;
+    let value = "v".repeat(4096);
+
+    for iter in 0..1000 {
+        store
+            .set(format!("key{}", iter % 10), format!("{}{}", value, iter))
+            .await?;
+    }
+
+    // 1000 overwrites of 4 KiB each cross the compaction threshold a few times.
+    let mut compacted = false;
+    for _ in 0..50 {
+        if dir_size(temp_dir.path()) < 1024 * 1024 {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(compacted, "data directory was not compacted");
+
+    assert_latest(&store, &value, 990).await?;
+    drop(store);
+    assert_latest(&Store::open(temp_dir.path(), 4)?, &value, 990).await
+}
+
+/// A JSON document of about 4 KiB, which compresses well.
+fn json_value(id: usize) -> String {
+    let tags: Vec<_> = (0..500).map(|i| format!("\"tag{}\"", i % 10)).collect();
+    format!(
+        "{{\"id\": {}, \"name\": \"user{}\", \"tags\": [{}]}}",
+        id,
+        id,
+        tags.join(", ")
+    )
+}
+
+// Logs with both compressed and uncompressed records replay, and compaction
+// compresses the older records.
+#[tokio::test(flavor = "multi_thread")]
+async fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 4)?;
+    for id in 0..100 {
+        store.set(format!("key{}", id), json_value(id)).await?;
+    }
+    drop(store);
+    let uncompressed = dir_size(temp_dir.path());
+    assert!(uncompressed > 100 * 1024);
+
+    let options = KvStoreOptions::new()
+        .concurrency(4)
+        .compression(Compression::Lz4)
+        .compaction_threshold(64 * 1024);
+    let store = Store::open_with(temp_dir.path(), options.clone())?;
+    for id in 100..200 {
+        store.set(format!("key{}", id), json_value(id)).await?;
+    }
+    assert!(dir_size(temp_dir.path()) < uncompressed + 20 * 1024);
+    for id in 0..200 {
+        assert_eq!(store.get(format!("key{}", id)).await?, Some(json_value(id)));
+    }
+
+    // overwriting most of the uncompressed keys compacts their log
+    for id in 0..60 {
+        store.set(format!("key{}", id), json_value(id + 1)).await?;
+    }
+    let mut compacted = false;
+    for _ in 0..50 {
+        if dir_size(temp_dir.path()) < 50 * 1024 {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(compacted, "uncompressed records were not compacted");
+    drop(store);
+
+    let store = Store::open(temp_dir.path(), 4)?;
+    for id in 0..200 {
+        let expected = json_value(if id < 60 { id + 1 } else { id });
+        assert_eq!(store.get(format!("key{}", id)).await?, Some(expected));
+    }
+    Ok(())
+}
+
+/// Returns whether any file in `path` contains `bytes`.
+fn files_contain(path: &Path, bytes: &[u8]) -> bool {
+    WalkDir::new(path)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .any(|entry| {
+            let contents = fs::read(entry.path()).unwrap();
+            contents.windows(bytes.len()).any(|window| window == bytes)
+        })
+}
+
+fn log_files(path: &Path) -> Vec<PathBuf> {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect()
+}
+
+/// Waits until the log files `old_logs` in `path` are compacted away.
+fn wait_for_compaction(path: &Path, old_logs: &[PathBuf]) {
+    for _ in 0..50 {
+        if log_files(path).iter().all(|log| !old_logs.contains(log)) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("log files were not compacted");
+}
+
+/// Opens the store in `path` and reads `key0` to `key9`.
+async fn read_secrets(path: &Path, options: KvStoreOptions) -> Result<()> {
+    let store = Store::open_with(path, options)?;
+    for id in 0..10 {
+        let expected = format!("secret{}", id);
+        assert_eq!(store.get(format!("key{}", id)).await?, Some(expected));
+    }
+    Ok(())
+}
+
+// Values are encrypted on disk and records sealed with another key are rejected,
+// until the store is rekeyed.
+#[tokio::test(flavor = "multi_thread")]
+async fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let (key, new_key) = (EncryptionKey::new([1; 32]), EncryptionKey::new([2; 32]));
+    let options = |key: &EncryptionKey| {
+        KvStoreOptions::new()
+            .concurrency(4)
+            .encryption_key(key.clone())
+    };
+
+    let store = Store::open(path, 4)?;
+    for id in 0..5 {
+        store
+            .set(format!("key{}", id), format!("secret{}", id))
+            .await?;
+    }
+    drop(store);
+    assert!(files_contain(path, b"secret"));
+    assert!(read_secrets(path, options(&key)).await.is_err());
+
+    // encrypts the existing records
+    let old_logs = log_files(path);
+    let store = Store::open_with(path, options(&key).rekey_from(None))?;
+    wait_for_compaction(path, &old_logs);
+    let mut batch = WriteBatch::new();
+    for id in 5..10 {
+        let (key, value) = (format!("key{}", id), format!("secret{}", id));
+        batch.set(key.into_bytes(), value.into_bytes());
+    }
+    store.write_batch(batch).await?;
+    store
+        .set_with_ttl(
+            b"expiring".to_vec(),
+            b"secret".to_vec(),
+            Duration::from_secs(60),
+        )
+        .await?;
+    drop(store);
+    assert!(!files_contain(path, b"secret"));
+    read_secrets(path, options(&key)).await?;
+    assert!(read_secrets(path, KvStoreOptions::new().concurrency(4))
+        .await
+        .is_err());
+    assert!(read_secrets(path, options(&new_key)).await.is_err());
+
+    // rotates the key
+    let old_logs = log_files(path);
+    let store = Store::open_with(path, options(&new_key).rekey_from(Some(key.clone())))?;
+    wait_for_compaction(path, &old_logs);
+    drop(store);
+    read_secrets(path, options(&new_key)).await?;
+    assert!(read_secrets(path, options(&key)).await.is_err());
+    assert!(!files_contain(path, b"secret"));
+    Ok(())
+}
+
+// Reads are served from the value cache until a key is written again, also after
+// compaction moved its value.
+#[tokio::test(flavor = "multi_thread")]
+async fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let options = KvStoreOptions::new()
+        .concurrency(4)
+        .compaction_threshold(64 * 1024);
+    let store = Store::open_with(path, options.clone())?;
+    for id in 0..10 {
+        store
+            .set(format!("key{}", id), format!("value{}", id))
+            .await?;
+    }
+    for _ in 0..2 {
+        for id in 0..10 {
+            let value = store.get(format!("key{}", id)).await?;
+            assert_eq!(value, Some(format!("value{}", id)));
+        }
+    }
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (10, 10, 10));
+
+    store.set("key0".to_owned(), "new".to_owned()).await?;
+    store.remove("key1".to_owned()).await?;
+    assert_eq!(store.get("key0".to_owned()).await?, Some("new".to_owned()));
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    assert_eq!(store.cache_stats().entries, 9);
+
+    let old_logs = log_files(path);
+    let filler = "f".repeat(4096);
+    for iter in 0..100 {
+        store
+            .set("filler".to_owned(), format!("{}{}", filler, iter))
+            .await?;
+    }
+    wait_for_compaction(path, &old_logs);
+    let misses = store.cache_stats().misses;
+    for id in 2..10 {
+        let value = store.get(format!("key{}", id)).await?;
+        assert_eq!(value, Some(format!("value{}", id)));
+    }
+    assert_eq!(store.cache_stats().misses, misses);
+    drop(store);
+
+    let store = Store::open_with(path, options.cache_size(0))?;
+    for _ in 0..2 {
+        assert_eq!(store.get("key0".to_owned()).await?, Some("new".to_owned()));
+    }
+    assert_eq!(store.cache_stats().hits, 0);
+    Ok(())
+}
+
+/// Checks the values of `key0` to `key49` with concurrent reads.
+async fn assert_concurrent_reads(store: &Store, value: &str) -> Result<()> {
+    let handles: Vec<_> = (0..50)
+        .map(|id| tokio::spawn(store.get(format!("key{}", id))))
+        .collect();
+    for (id, handle) in handles.into_iter().enumerate() {
+        let expected = format!("{}{}", value, 150 + id);
+        assert_eq!(handle.await.unwrap()?, Some(expected));
+    }
+    Ok(())
+}
+
+// Reads beyond the concurrency open extra readers instead of panicking, and failed
+// reads return their reader to the pool.
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_gets_beyond_reader_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every get runs on its own thread and reads through a pooled reader
+    let options = KvStoreOptions::new()
+        .concurrency(1)
+        .reader_pool_size(1)
+        .cache_size(0)
+        .mmap_reads(false);
+    let store = KvStore::<NaiveThreadPool>::open_with(temp_dir.path(), options)?;
+    store.set("broken".to_owned(), "value".to_owned()).await?;
+    // large values keep the reads running long enough to overlap
+    let value = "v".repeat(256 * 1024);
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("{}{}", value, i))
+            .await?;
+    }
+
+    // damage the value of the first record: a 20 bytes header and the 6 bytes key
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.seek(SeekFrom::Start(20 + 6 + 2))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    let handles: Vec<_> = (0..500)
+        .map(|i| {
+            let key = if i % 10 == 0 {
+                "broken".to_owned()
+            } else {
+                format!("key{}", i % 100)
+            };
+            tokio::spawn(store.get(key))
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        match handle.await.unwrap() {
+            Err(KvsError::CorruptedLog { gen, offset, .. }) if i % 10 == 0 => {
+                assert_eq!((gen, offset), (1, 0))
+            }
+            Ok(res) if i % 10 != 0 => {
+                assert_eq!(res, Some(format!("{}{}", value, i % 100)))
+            }
+            res => panic!("unexpected result of get {}: {:?}", i, res),
+        }
+    }
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some(format!("{}1", value))
+    );
+    Ok(())
+}
+
+// The extra readers of a read-only store keep reading the files it was opened with
+// after the writer compacted them away.
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_gets_beyond_reader_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let options = KvStoreOptions::new()
+        .max_file_size(256 * 1024)
+        .compaction_threshold(512 * 1024);
+    let store = Store::open_with(path, options)?;
+    let value = "v".repeat(64 * 1024);
+    for i in 0..20 {
+        store
+            .set(format!("key{}", i), format!("{}{}", value, i))
+            .await?;
+    }
+    let old_logs = log_files(path);
+
+    let options = KvStoreOptions::new()
+        .concurrency(1)
+        .reader_pool_size(1)
+        .cache_size(0)
+        .mmap_reads(false)
+        .read_only(true);
+    let read_only = KvStore::<NaiveThreadPool>::open_with(path, options)?;
+    for _ in 0..40 {
+        store.set("filler".to_owned(), value.clone()).await?;
+    }
+    wait_for_compaction(path, &old_logs);
+
+    let handles: Vec<_> = (0..200)
+        .map(|i| tokio::spawn(read_only.get(format!("key{}", i % 20))))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        let expected = format!("{}{}", value, i % 20);
+        assert_eq!(handle.await.unwrap()?, Some(expected));
+    }
+    Ok(())
+}
+
+// Sealed log files are read through memory maps, which are updated as logs roll over
+// and get compacted.
+#[tokio::test(flavor = "multi_thread")]
+async fn mmap_reads() -> Result<()> {
+    for mmap_reads in [true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .concurrency(4)
+            .cache_size(0)
+            .max_file_size(16 * 1024)
+            .compaction_threshold(64 * 1024)
+            .mmap_reads(mmap_reads);
+        let store = Store::open_with(temp_dir.path(), options.clone())?;
+        let value = "v".repeat(1024);
+        for iter in 0..200 {
+            store
+                .set(format!("key{}", iter % 50), format!("{}{}", value, iter))
+                .await?;
+        }
+        assert_concurrent_reads(&store, &value).await?;
+        drop(store);
+
+        let store = Store::open_with(temp_dir.path(), options.clone())?;
+        assert_concurrent_reads(&store, &value).await?;
+        let read_only = Store::open_with(temp_dir.path(), options.read_only(true))?;
+        assert_concurrent_reads(&read_only, &value).await?;
+    }
+    Ok(())
+}
+
+// Compaction generations get a hint file which is used on open, and ignored if damaged.
+#[tokio::test(flavor = "multi_thread")]
+async fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 4)?;
+    let value = "v".repeat(4096);
+    for iter in 0..500 {
+        store
+            .set(format!("key{}", iter % 10), format!("{}{}", value, iter))
+            .await?;
+    }
+
+    let hint_exists = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .any(|entry| entry.unwrap().path().extension() == Some("hint".as_ref()))
+    };
+    for _ in 0..50 {
+        if hint_exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(hint_exists(), "no hint file was written");
+    drop(store);
+
+    assert_latest(&Store::open(temp_dir.path(), 4)?, &value, 490).await?;
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            let mut file = OpenOptions::new().write(true).open(&path)?;
+            file.seek(SeekFrom::Start(10))?;
+            file.write_all(b"garbage")?;
+        }
+    }
+    assert_latest(&Store::open(temp_dir.path(), 4)?, &value, 490).await?;
+    Ok(())
+}
+
+// Writes roll over to a new log file once the active one passes the size limit.
+#[tokio::test]
+async fn max_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .concurrency(2)
+        .max_file_size(1024)
+        .reader_pool_size(1);
+    let store = Store::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+    drop(store);
+
+    let logs: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    assert!(logs.len() > 1, "no new log file was started");
+    for log in logs {
+        assert!(fs::metadata(log)?.len() < 1024 + 64);
+    }
+
+    let store = Store::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// A read-only store serves reads but rejects writes, and a missing directory is
+// only created if asked to.
+#[tokio::test]
+async fn read_only_and_create_if_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("db");
+    let options = KvStoreOptions::new().concurrency(2);
+    assert!(Store::open_with(&path, options.clone().create_if_missing(false)).is_err());
+    assert!(!path.exists());
+
+    let store = Store::open_with(&path, options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    drop(store);
+
+    let store = Store::open_with(&path, options.read_only(true))?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        store.set("key1".to_owned(), "value2".to_owned()).await,
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()).await,
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(!path.join("2.log").exists());
+    Ok(())
+}
+
+// Compacting the oldest of many rolled over logs keeps removed keys removed.
+#[tokio::test(flavor = "multi_thread")]
+async fn compaction_with_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .concurrency(4)
+        .max_file_size(64 * 1024)
+        .compaction_threshold(256 * 1024);
+    let store = Store::open_with(temp_dir.path(), options.clone())?;
+    let value = "v".repeat(4096);
+    for iter in 0..600 {
+        store
+            .set(format!("key{}", iter % 10), format!("{}{}", value, iter))
+            .await?;
+        if iter == 300 {
+            store.remove("key0".to_owned()).await?;
+        }
+    }
+    for key_id in 1..5 {
+        store.remove(format!("key{}", key_id)).await?;
+    }
+
+    let mut compacted = false;
+    for _ in 0..50 {
+        if dir_size(temp_dir.path()) < 1024 * 1024 {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(compacted, "data directory was not compacted");
+    drop(store);
+
+    let store = Store::open_with(temp_dir.path(), options)?;
+    for key_id in 1..5 {
+        assert_eq!(store.get(format!("key{}", key_id)).await?, None);
+    }
+    for key_id in (0..10).filter(|key_id| !(1..5).contains(key_id)) {
+        let expected = format!("{}{}", value, 590 + key_id);
+        assert_eq!(store.get(format!("key{}", key_id)).await?, Some(expected));
+    }
+    Ok(())
+}
+
+// Only one store can have a directory open at a time, also across engines.
+#[tokio::test]
+async fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        Store::open(temp_dir.path(), 2),
+        Err(KvsError::Locked(_))
+    ));
+    assert!(matches!(
+        SledKvsEngine::<SharedQueueThreadPool>::open(temp_dir.path(), 2),
+        Err(KvsError::Locked(_))
+    ));
+    drop(clone);
+    drop(Store::open(temp_dir.path(), 2)?);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::<SharedQueueThreadPool>::open(sled_dir.path(), 2)?;
+    assert!(matches!(
+        Store::open(sled_dir.path(), 2),
+        Err(KvsError::Locked(_))
+    ));
+    drop(engine);
+    Ok(())
+}
+
+// A read-only store can be opened next to a writer and sees the data as of its opening.
+#[tokio::test]
+async fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    let files = fs::read_dir(temp_dir.path())?.count();
+
+    let read_only = Store::open_read_only(temp_dir.path(), 2)?;
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), files);
+    assert!(matches!(
+        read_only.set("key2".to_owned(), "value2".to_owned()).await,
+        Err(KvsError::ReadOnly)
+    ));
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    for _ in 0..4 {
+        assert_eq!(
+            read_only.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(read_only.get("key2".to_owned()).await?, None);
+    }
+
+    drop(store);
+    let read_only = Store::open_read_only(temp_dir.path(), 2)?;
+    assert_eq!(
+        read_only.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+async fn check_binary_keys(engine: impl KvsEngine) -> Result<()> {
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x00, 0x80, 0xff];
+    engine.set_bytes(key.clone(), value.clone()).await?;
+    assert_eq!(engine.get_bytes(key.clone()).await?, Some(value));
+    engine.set_bytes(b"key".to_vec(), vec![0xff]).await?;
+    assert!(matches!(
+        engine.get("key".to_owned()).await,
+        Err(KvsError::Utf8(_))
+    ));
+    engine.remove_bytes(key.clone()).await?;
+    assert_eq!(engine.get_bytes(key).await?, None);
+    Ok(())
+}
+
+async fn check_binary_keys_reopened(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.get_bytes(b"key".to_vec()).await?, Some(vec![0xff]));
+    Ok(())
+}
+
+async fn check_scans(engine: impl KvsEngine) -> Result<()> {
+    for key in ["a", "b", "ba", "bb", "c"] {
+        engine.set(key.to_owned(), key.to_uppercase()).await?;
+    }
+    engine.set_bytes(vec![b'b', 0xff], vec![0xff]).await?;
+    engine.set_bytes(vec![0xff, 0xff], vec![]).await?;
+    let pair = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+
+    assert_eq!(
+        engine.scan(b"b".to_vec()..b"c".to_vec(), 100).await?,
+        vec![
+            pair(b"b", b"B"),
+            pair(b"ba", b"BA"),
+            pair(b"bb", b"BB"),
+            pair(&[b'b', 0xff], &[0xff]),
+        ]
+    );
+    assert_eq!(
+        engine.scan(b"ba".to_vec().., 2).await?,
+        vec![pair(b"ba", b"BA"), pair(b"bb", b"BB")]
+    );
+    assert_eq!(engine.scan(.., 0).await?, vec![]);
+    assert_eq!(engine.scan(.., 100).await?.len(), 7);
+    assert_eq!(
+        engine.scan_prefix(b"b".to_vec(), 2).await?,
+        vec![pair(b"b", b"B"), pair(b"ba", b"BA")]
+    );
+    assert_eq!(
+        engine.scan_prefix(vec![0xff], 100).await?,
+        vec![pair(&[0xff, 0xff], b"")]
+    );
+    Ok(())
+}
+
+async fn check_write_batch(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key1".to_vec());
+    batch.remove(b"missing".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key3".to_vec());
+    engine.write_batch(batch).await?;
+
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        engine.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(engine.get("key3".to_owned()).await?, None);
+    engine.write_batch(WriteBatch::new()).await?;
+    Ok(())
+}
+
+async fn check_write_batch_reopened(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(
+        engine.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+// A batch cut short by a crash is dropped as a whole on open.
+#[tokio::test]
+async fn truncate_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"a".to_vec(), b"1".to_vec());
+    batch.remove(b"key1".to_vec());
+    batch.set(b"b".to_vec(), b"2".to_vec());
+    store.write_batch(batch).await?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    assert_eq!(fs::metadata(&log)?.len(), 118);
+    OpenOptions::new().write(true).open(&log)?.set_len(110)?;
+
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(fs::metadata(&log)?.len(), 30);
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("a".to_owned()).await?, None);
+    assert_eq!(store.get("b".to_owned()).await?, None);
+    Ok(())
+}
+
+async fn check_compare_and_swap(engine: impl KvsEngine) -> Result<()> {
+    let key = || b"lease".to_vec();
+    assert_eq!(
+        engine
+            .compare_and_swap(key(), None, Some(b"a".to_vec()))
+            .await?,
+        Ok(())
+    );
+    assert_eq!(
+        engine
+            .compare_and_swap(key(), None, Some(b"b".to_vec()))
+            .await?,
+        Err(CompareAndSwapError {
+            current: Some(b"a".to_vec())
+        })
+    );
+    assert_eq!(
+        engine
+            .compare_and_swap(key(), Some(b"a".to_vec()), None)
+            .await?,
+        Ok(())
+    );
+    assert_eq!(engine.get_bytes(key()).await?, None);
+    assert_eq!(
+        engine
+            .compare_and_swap(key(), Some(b"a".to_vec()), None)
+            .await?,
+        Err(CompareAndSwapError { current: None })
+    );
+
+    // concurrent increments don't lose updates
+    engine.set("counter".to_owned(), "0".to_owned()).await?;
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    let mut current = engine.get_bytes(b"counter".to_vec()).await?;
+                    loop {
+                        let n: u64 = String::from_utf8(current.clone().unwrap())?
+                            .parse()
+                            .unwrap();
+                        let new = Some((n + 1).to_string().into_bytes());
+                        match engine
+                            .compare_and_swap(b"counter".to_vec(), current, new)
+                            .await?
+                        {
+                            Ok(()) => break,
+                            Err(e) => current = e.current,
+                        }
+                    }
+                }
+                Ok::<_, KvsError>(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    assert_eq!(
+        engine.get("counter".to_owned()).await?,
+        Some("80".to_owned())
+    );
+    Ok(())
+}
+
+async fn check_compare_and_swap_reopened(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(
+        engine.get("counter".to_owned()).await?,
+        Some("80".to_owned())
+    );
+    Ok(())
+}
+
+async fn check_incr_and_append(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.incr_by(b"counter".to_vec(), 5).await?, 5);
+    assert_eq!(engine.incr_by(b"counter".to_vec(), -7).await?, -2);
+    engine.set("max".to_owned(), i64::MAX.to_string()).await?;
+    assert!(matches!(
+        engine.incr_by(b"max".to_vec(), 1).await,
+        Err(KvsError::IntegerOverflow)
+    ));
+    engine.set("text".to_owned(), "abc".to_owned()).await?;
+    assert!(matches!(
+        engine.incr_by(b"text".to_vec(), 1).await,
+        Err(KvsError::NotAnInteger)
+    ));
+    assert_eq!(engine.get("text".to_owned()).await?, Some("abc".to_owned()));
+
+    engine.append(b"text".to_vec(), b"def".to_vec()).await?;
+    engine.append(b"log".to_vec(), b"1".to_vec()).await?;
+    assert_eq!(
+        engine.get("text".to_owned()).await?,
+        Some("abcdef".to_owned())
+    );
+    assert_eq!(engine.get("log".to_owned()).await?, Some("1".to_owned()));
+
+    // concurrent increments and appends aren't lost
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    engine.incr_by(b"counter".to_vec(), 1).await?;
+                    engine.append(b"log".to_vec(), b"x".to_vec()).await?;
+                }
+                Ok::<_, KvsError>(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    assert_eq!(
+        engine.get("counter".to_owned()).await?,
+        Some("78".to_owned())
+    );
+    assert_eq!(engine.get_bytes(b"log".to_vec()).await?.unwrap().len(), 81);
+    Ok(())
+}
+
+async fn check_incr_and_append_reopened(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(
+        engine.get("counter".to_owned()).await?,
+        Some("78".to_owned())
+    );
+    Ok(())
+}
+
+async fn check_take_and_remove_if_exists(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    engine.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        engine.take(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(engine.take(b"key1".to_vec()).await?, None);
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+
+    assert!(engine.remove_if_exists(b"key2".to_vec()).await?);
+    assert!(!engine.remove_if_exists(b"key2".to_vec()).await?);
+    assert_eq!(engine.get("key2".to_owned()).await?, None);
+    assert!(matches!(
+        engine.remove("key2".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // a value is taken only once
+    engine.set("job".to_owned(), "payload".to_owned()).await?;
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.take(b"job".to_vec()).await })
+        })
+        .collect();
+    let mut taken = 0;
+    for task in tasks {
+        if let Some(value) = task.await.unwrap()? {
+            assert_eq!(value, b"payload".to_vec());
+            taken += 1;
+        }
+    }
+    assert_eq!(taken, 1);
+    Ok(())
+}
+
+async fn check_take_and_remove_if_exists_reopened(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.scan(.., 100).await?.len(), 0);
+    Ok(())
+}
+
+async fn check_ttl(engine: impl KvsEngine) -> Result<()> {
+    let ttl = Duration::from_millis(500);
+    engine
+        .set_with_ttl(b"session".to_vec(), b"token".to_vec(), ttl)
+        .await?;
+    let expired = Instant::now() + ttl;
+    engine
+        .set_with_ttl(b"renewed".to_vec(), b"old".to_vec(), ttl)
+        .await?;
+    engine.set("renewed".to_owned(), "new".to_owned()).await?;
+    engine.set("plain".to_owned(), "value".to_owned()).await?;
+
+    let left = engine.ttl(b"session".to_vec()).await?.unwrap();
+    assert!(left <= ttl && left > Duration::ZERO);
+    assert_eq!(engine.ttl(b"plain".to_vec()).await?, None);
+    assert_eq!(engine.ttl(b"renewed".to_vec()).await?, None);
+    assert!(matches!(
+        engine.ttl(b"missing".to_vec()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(
+        engine.get("session".to_owned()).await?,
+        Some("token".to_owned())
+    );
+
+    tokio::time::sleep_until(expired.into()).await;
+    assert_eq!(engine.get("session".to_owned()).await?, None);
+    assert!(matches!(
+        engine.ttl(b"session".to_vec()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        engine.remove("session".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.scan(.., 100).await?.len(), 2);
+    assert_eq!(
+        engine.get("renewed".to_owned()).await?,
+        Some("new".to_owned())
+    );
+    Ok(())
+}
+
+// The expiry time is stored in the log, and an expired value doesn't bring back
+// the one it replaced.
+#[tokio::test]
+async fn ttl_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.set("key1".to_owned(), "old".to_owned()).await?;
+    let ttl = Duration::from_millis(500);
+    store
+        .set_with_ttl(b"key1".to_vec(), b"new".to_vec(), ttl)
+        .await?;
+    let expired = Instant::now() + ttl;
+    store
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+    drop(store);
+
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("new".to_owned()));
+    assert!(store.ttl(b"key2".to_vec()).await?.unwrap() > Duration::from_secs(3500));
+    drop(store);
+
+    tokio::time::sleep_until(expired.into()).await;
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// Expired keys are removed in the background and compacted away.
+#[tokio::test(flavor = "multi_thread")]
+async fn reap_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .concurrency(2)
+        .compaction_threshold(1024)
+        .reap_interval(Duration::from_millis(50));
+    let store = Store::open_with(temp_dir.path(), options)?;
+    let ttl = Duration::from_millis(200);
+    for i in 0..1000 {
+        let key = format!("key{}", i).into_bytes();
+        store.set_with_ttl(key, vec![0; 100], ttl).await?;
+    }
+    store.set("kept".to_owned(), "value".to_owned()).await?;
+    // compactions may already run while the keys are being written
+    let written = 1000 * 100;
+
+    // wait for the reaper to trigger a compaction
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if dir_size(temp_dir.path()) < written / 10 {
+            break;
+        }
+    }
+    assert!(dir_size(temp_dir.path()) < written / 10);
+    assert_eq!(
+        store.get("kept".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    drop(store);
+
+    let store = Store::open(temp_dir.path(), 2)?;
+    assert_eq!(store.scan(.., 2000).await?.len(), 1);
+    Ok(())
+}
+
+async fn check_snapshot(engine: impl KvsEngine) -> Result<()> {
+    for i in 1..=3 {
+        engine
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+    let snapshot = engine.snapshot().await?;
+
+    engine.set("key1".to_owned(), "new".to_owned()).await?;
+    engine.remove("key2".to_owned()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key0".to_vec(), b"value0".to_vec());
+    batch.set(b"key4".to_vec(), b"value4".to_vec());
+    engine.write_batch(batch).await?;
+
+    assert_eq!(
+        snapshot.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        snapshot.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(snapshot.get("key4".to_owned()).await?, None);
+    let expected: Vec<_> = (1..=3)
+        .map(|i| {
+            (
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        })
+        .collect();
+    assert_eq!(snapshot.scan(.., 100).await?, expected);
+    assert_eq!(
+        snapshot.scan_prefix(b"key".to_vec(), 2).await?,
+        expected[..2]
+    );
+
+    assert_eq!(engine.get("key1".to_owned()).await?, Some("new".to_owned()));
+    let newer = engine.snapshot().await?;
+    assert_eq!(newer.get("key1".to_owned()).await?, Some("new".to_owned()));
+    assert_eq!(newer.get("key2".to_owned()).await?, None);
+    assert_eq!(newer.scan(.., 100).await?.len(), 4);
+    drop(snapshot);
+    assert_eq!(newer.scan(.., 100).await?.len(), 4);
+    Ok(())
+}
+
+// Compaction keeps the log files a snapshot reads from until it is dropped.
+#[tokio::test(flavor = "multi_thread")]
+async fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 4)?;
+    let value = "v".repeat(4096);
+    for iter in 0..10 {
+        store
+            .set(format!("key{}", iter), format!("{}{}", value, iter))
+            .await?;
+    }
+    let snapshot = store.snapshot().await?;
+    for iter in 10..1000 {
+        store
+            .set(format!("key{}", iter % 10), format!("{}{}", value, iter))
+            .await?;
+    }
+
+    // wait for a compaction, which can't delete the overwritten values
+    let compacted = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .any(|entry| entry.unwrap().path().extension() == Some("hint".as_ref()))
+    };
+    for _ in 0..50 {
+        if compacted() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(compacted(), "data directory was not compacted");
+    for key_id in 0..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id)).await?,
+            Some(format!("{}{}", value, key_id))
+        );
+    }
+    assert_latest(&store, &value, 990).await?;
+    assert!(dir_size(temp_dir.path()) > 1000 * 4096);
+
+    drop(snapshot);
+    let mut compacted = false;
+    for _ in 0..50 {
+        if dir_size(temp_dir.path()) < 1024 * 1024 {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(compacted, "stale log files were not deleted");
+    assert_latest(&store, &value, 990).await?;
+    drop(store);
+    assert_latest(&Store::open(temp_dir.path(), 4)?, &value, 990).await
+}
+
+/// Moves `amount` from one balance to another in a transaction, retrying on conflicts.
+async fn transfer(engine: impl KvsEngine, from: &str, to: &str, amount: i64) -> Result<()> {
+    loop {
+        let mut tx = engine.begin();
+        let from_balance: i64 = tx.get(from.to_owned()).await?.unwrap().parse().unwrap();
+        let to_balance: i64 = tx.get(to.to_owned()).await?.unwrap().parse().unwrap();
+        tx.set(from.to_owned(), (from_balance - amount).to_string());
+        tx.set(to.to_owned(), (to_balance + amount).to_string());
+        match tx.commit().await {
+            Err(KvsError::TransactionConflict) => continue,
+            res => return res,
+        }
+    }
+}
+
+async fn check_transactions(engine: impl KvsEngine) -> Result<()> {
+    // a read key written by someone else fails the commit
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    let mut tx = engine.begin();
+    assert_eq!(tx.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    tx.set("key2".to_owned(), "value2".to_owned());
+    assert_eq!(tx.get("key2".to_owned()).await?, Some("value2".to_owned()));
+    engine.set("key1".to_owned(), "changed".to_owned()).await?;
+    assert!(matches!(
+        tx.commit().await,
+        Err(KvsError::TransactionConflict)
+    ));
+    assert_eq!(engine.get("key2".to_owned()).await?, None);
+
+    // so does a missing key which was created
+    let mut tx = engine.begin();
+    assert_eq!(tx.get("key3".to_owned()).await?, None);
+    tx.set("key3".to_owned(), "mine".to_owned());
+    engine.set("key3".to_owned(), "theirs".to_owned()).await?;
+    assert!(matches!(
+        tx.commit().await,
+        Err(KvsError::TransactionConflict)
+    ));
+
+    let mut tx = engine.begin();
+    assert!(matches!(
+        tx.remove("missing".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    tx.remove("key1".to_owned()).await?;
+    assert_eq!(tx.get("key1".to_owned()).await?, None);
+    tx.set("key2".to_owned(), "value2".to_owned());
+    tx.commit().await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        engine.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    let mut tx = engine.begin();
+    tx.set("key2".to_owned(), "aborted".to_owned());
+    tx.abort();
+    assert_eq!(
+        engine.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    // concurrent transfers keep the total
+    engine.set("alice".to_owned(), "100".to_owned()).await?;
+    engine.set("bob".to_owned(), "0".to_owned()).await?;
+    let tasks: Vec<_> = (0..8)
+        .map(|i| {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    if i % 2 == 0 {
+                        transfer(engine.clone(), "alice", "bob", 2).await?;
+                    } else {
+                        transfer(engine.clone(), "bob", "alice", 1).await?;
+                    }
+                }
+                Ok::<_, KvsError>(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    assert_eq!(engine.get("alice".to_owned()).await?, Some("60".to_owned()));
+    assert_eq!(engine.get("bob".to_owned()).await?, Some("40".to_owned()));
+    Ok(())
+}
+
+async fn check_transactions_reopened(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.get("alice".to_owned()).await?, Some("60".to_owned()));
+    Ok(())
+}
+
+async fn check_keyspaces(engine: impl KvsEngine) -> Result<()> {
+    engine.create_keyspace("users").await?;
+    engine.create_keyspace("orders").await?;
+    assert!(matches!(
+        engine.create_keyspace("users").await,
+        Err(KvsError::KeyspaceExists(_))
+    ));
+    for name in ["", "a/b", "..", &"x".repeat(65)] {
+        assert!(matches!(
+            engine.create_keyspace(name).await,
+            Err(KvsError::InvalidKeyspaceName(_))
+        ));
+    }
+    assert!(matches!(
+        engine.keyspace("missing"),
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+    assert_eq!(engine.list_keyspaces().await?, vec!["orders", "users"]);
+
+    // the same key lives apart in every keyspace
+    let users = engine.keyspace("users")?;
+    let orders = users.keyspace("orders")?;
+    engine.set("key1".to_owned(), "default".to_owned()).await?;
+    users.set("key1".to_owned(), "user".to_owned()).await?;
+    users.set("key2".to_owned(), "user".to_owned()).await?;
+    orders.set("key1".to_owned(), "order".to_owned()).await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("default".to_owned())
+    );
+    assert_eq!(users.get("key1".to_owned()).await?, Some("user".to_owned()));
+    assert_eq!(
+        orders.get("key1".to_owned()).await?,
+        Some("order".to_owned())
+    );
+    assert_eq!(engine.get("key2".to_owned()).await?, None);
+    assert_eq!(
+        users.scan(.., 10).await?,
+        vec![
+            (b"key1".to_vec(), b"user".to_vec()),
+            (b"key2".to_vec(), b"user".to_vec()),
+        ]
+    );
+    users.remove("key2".to_owned()).await?;
+    assert!(matches!(
+        orders.remove("key2".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    engine.drop_keyspace("orders").await?;
+    drop(orders);
+    assert_eq!(engine.list_keyspaces().await?, vec!["users"]);
+    assert!(matches!(
+        engine.keyspace("orders"),
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+    assert!(matches!(
+        engine.drop_keyspace("orders").await,
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+    engine.create_keyspace("orders").await?;
+    let orders = engine.keyspace("orders")?;
+    assert_eq!(orders.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+async fn check_keyspaces_reopened(engine: impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.list_keyspaces().await?, vec!["orders", "users"]);
+    let users = engine.keyspace("users")?;
+    assert_eq!(users.get("key1".to_owned()).await?, Some("user".to_owned()));
+    assert_eq!(users.get("key2".to_owned()).await?, None);
+    Ok(())
+}
+
+// A read-only store serves the keyspaces it was opened with but can't create any.
+#[tokio::test]
+async fn read_only_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.create_keyspace("users").await?;
+    let users = store.keyspace("users")?;
+    users.set("key1".to_owned(), "user".to_owned()).await?;
+    drop((store, users));
+
+    let store = Store::open_read_only(temp_dir.path(), 2)?;
+    assert_eq!(store.list_keyspaces().await?, vec!["users"]);
+    let users = store.keyspace("users")?;
+    assert_eq!(users.get("key1".to_owned()).await?, Some("user".to_owned()));
+    assert!(matches!(
+        store.create_keyspace("other").await,
+        Err(KvsError::ReadOnly)
+    ));
+    Ok(())
+}
+
+// Handles of a dropped keyspace refuse writes which would be lost with its files.
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_to_dropped_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path(), 2)?;
+    store.create_keyspace("users").await?;
+    let users = store.keyspace("users")?;
+    users.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.drop_keyspace("users").await?;
+
+    assert!(matches!(
+        users.set("key2".to_owned(), "value2".to_owned()).await,
+        Err(KvsError::KeyspaceNotFound(name)) if name == "users"
+    ));
+    assert!(matches!(
+        users.remove("key1".to_owned()).await,
+        Err(KvsError::KeyspaceNotFound(_))
+    ));
+
+    // a keyspace created again under the name doesn't get the refused writes
+    store.create_keyspace("users").await?;
+    let users = store.keyspace("users")?;
+    assert_eq!(users.get("key2".to_owned()).await?, None);
+    users.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        users.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+engine_tests! {
+    // Keys and values are arbitrary bytes in both engines.
+    #[tokio::test]
+    binary_keys_and_values: check_binary_keys(2) then check_binary_keys_reopened;
+
+    // Both engines scan key ranges and prefixes in key order.
+    #[tokio::test]
+    scans: check_scans(2);
+
+    // Both engines apply all writes of a batch, skipping removals of missing keys.
+    #[tokio::test]
+    write_batch: check_write_batch(2) then check_write_batch_reopened;
+
+    // Both engines swap a value only if it is the expected one.
+    #[tokio::test(flavor = "multi_thread")]
+    compare_and_swap: check_compare_and_swap(4) then check_compare_and_swap_reopened;
+
+    // Both engines increment and append atomically.
+    #[tokio::test(flavor = "multi_thread")]
+    incr_and_append: check_incr_and_append(4) then check_incr_and_append_reopened;
+
+    // Both engines return the removed value and skip missing keys on request.
+    #[tokio::test(flavor = "multi_thread")]
+    take_and_remove_if_exists: check_take_and_remove_if_exists(4)
+        then check_take_and_remove_if_exists_reopened;
+
+    // Both engines hide keys once their TTL has passed.
+    #[tokio::test]
+    ttl: check_ttl(2);
+
+    // Both engines read from a snapshot as of the time it was taken.
+    #[tokio::test(flavor = "multi_thread")]
+    snapshot: check_snapshot(4);
+
+    // Both engines commit a transaction only if the keys it read didn't change.
+    #[tokio::test(flavor = "multi_thread")]
+    transactions: check_transactions(4) then check_transactions_reopened;
+
+    // Keyspaces hold their keys apart from each other, also after reopening.
+    #[tokio::test(flavor = "multi_thread")]
+    keyspaces: check_keyspaces(2) then check_keyspaces_reopened;
+}
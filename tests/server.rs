@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{server::KvsServer, KvStore, KvsClient, Result, WriteBatch};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+// A transaction is scoped to its connection and fails to commit if another
+// connection wrote a key it read.
+#[tokio::test(flavor = "multi_thread")]
+async fn transaction_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { KvsServer::new(store, listener).run().await });
+
+    let mut client1 = KvsClient::connect(addr).await?;
+    let mut client2 = KvsClient::connect(addr).await?;
+    client1.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    client1.begin().await?;
+    assert!(client1.begin().await.is_err());
+    assert_eq!(
+        client1.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    client1.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        client1.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(client2.get("key2".to_owned()).await?, None);
+    client1.commit().await?;
+    assert_eq!(
+        client2.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    client1.begin().await?;
+    assert_eq!(
+        client1.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    client1.remove("key1".to_owned()).await?;
+    client2.set("key1".to_owned(), "changed".to_owned()).await?;
+    let err = client1.commit().await.unwrap_err();
+    assert_eq!(err.to_string(), "Transaction conflict");
+    assert_eq!(
+        client1.get("key1".to_owned()).await?,
+        Some("changed".to_owned())
+    );
+
+    // writes the transaction can't hold are refused instead of bypassing it
+    client1.begin().await?;
+    let err = client1.take(b"key1".to_vec()).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The request can't be part of a transaction"
+    );
+    assert!(client1.remove_if_exists(b"key1".to_vec()).await.is_err());
+    assert!(client1.write_batch(WriteBatch::new()).await.is_err());
+    assert!(client1
+        .compare_and_swap(b"key1".to_vec(), None, Some(b"swapped".to_vec()))
+        .await
+        .is_err());
+    assert!(client1.incr_by(b"counter".to_vec(), 1).await.is_err());
+    assert!(client1
+        .append(b"key1".to_vec(), b"!".to_vec())
+        .await
+        .is_err());
+    assert!(client1
+        .set_with_ttl(b"key1".to_vec(), b"ttl".to_vec(), Duration::from_secs(60))
+        .await
+        .is_err());
+    client1.abort().await?;
+    assert_eq!(
+        client2.get("key1".to_owned()).await?,
+        Some("changed".to_owned())
+    );
+    assert_eq!(client2.get("counter".to_owned()).await?, None);
+
+    client1.begin().await?;
+    client1.set("key1".to_owned(), "aborted".to_owned()).await?;
+    client1.abort().await?;
+    assert!(client1.commit().await.is_err());
+    assert_eq!(
+        client2.get("key1".to_owned()).await?,
+        Some("changed".to_owned())
+    );
+    Ok(())
+}
+
+// A client selects the keyspace of its requests, and a transaction stays in
+// the keyspace it was begun in.
+#[tokio::test(flavor = "multi_thread")]
+async fn keyspace_over_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { KvsServer::new(store, listener).run().await });
+
+    let mut client = KvsClient::connect(addr).await?;
+    client.create_keyspace("users".to_owned()).await?;
+    assert_eq!(client.list_keyspaces().await?, vec!["users"]);
+    client.set("key1".to_owned(), "default".to_owned()).await?;
+    client.select_keyspace(Some("users".to_owned()));
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    client.set("key1".to_owned(), "user".to_owned()).await?;
+
+    client.begin().await?;
+    client.set("key2".to_owned(), "user".to_owned()).await?;
+    // the keyspace of the transaction can't be dropped under it
+    let err = client.drop_keyspace("users".to_owned()).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The request can't be part of a transaction"
+    );
+    assert!(client.create_keyspace("orders".to_owned()).await.is_err());
+    assert_eq!(client.list_keyspaces().await?, vec!["users"]);
+    client.select_keyspace(None);
+    let err = client.get("key2".to_owned()).await.unwrap_err();
+    assert_eq!(err.to_string(), "The transaction is in another keyspace");
+    client.commit().await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("default".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+
+    client.select_keyspace(Some("users".to_owned()));
+    assert_eq!(
+        client.get("key2".to_owned()).await?,
+        Some("user".to_owned())
+    );
+    client.drop_keyspace("users".to_owned()).await?;
+    let err = client.get("key1".to_owned()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Keyspace users not found");
+    assert!(client.list_keyspaces().await?.is_empty());
+    Ok(())
+}